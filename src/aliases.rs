@@ -0,0 +1,214 @@
+use evdev_rs::{Device, DeviceWrapper};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AliasError {
+    #[error("could not read aliases file `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error(transparent)]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid name regex for alias `{0}`")]
+    InvalidRegex(String, #[source] regex::Error),
+    #[error("alias `{0}` has nothing to match devices on")]
+    NoCriteria(String),
+}
+
+pub type AliasResult<T> = Result<T, AliasError>;
+
+/// One entry in the aliases file, e.g.
+///
+/// ```toml
+/// [left-joycon]
+/// vendor_id = 0x057e
+/// product_id = 0x2006
+/// name = "Joy-Con \\(L\\)"
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct AliasCriteria {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    uniq: Option<String>,
+    /// A regex that has to match somewhere in the device's name
+    name: Option<String>,
+    phys: Option<String>,
+}
+
+/// A human-chosen name for every device that matches all of the given
+/// criteria.
+#[derive(Debug)]
+pub struct Alias {
+    alias: String,
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    uniq: Option<String>,
+    name: Option<Regex>,
+    phys: Option<String>,
+}
+
+impl Alias {
+    fn new(alias: String, criteria: AliasCriteria) -> AliasResult<Self> {
+        let AliasCriteria {
+            vendor_id,
+            product_id,
+            uniq,
+            name,
+            phys,
+        } = criteria;
+        if vendor_id.is_none()
+            && product_id.is_none()
+            && uniq.is_none()
+            && name.is_none()
+            && phys.is_none()
+        {
+            return Err(AliasError::NoCriteria(alias));
+        }
+        let name = match name {
+            Some(name) => match Regex::new(&name) {
+                Ok(re) => Some(re),
+                Err(e) => return Err(AliasError::InvalidRegex(alias, e)),
+            },
+            None => None,
+        };
+        Ok(Self {
+            alias,
+            vendor_id,
+            product_id,
+            uniq,
+            name,
+            phys,
+        })
+    }
+
+    pub fn matches(&self, dev: &Device) -> bool {
+        self.vendor_id.map_or(true, |v| dev.vendor_id() == v)
+            && self.product_id.map_or(true, |p| dev.product_id() == p)
+            && self.uniq.as_deref().map_or(true, |u| dev.uniq() == Some(u))
+            && self
+                .name
+                .as_ref()
+                .map_or(true, |re| re.is_match(dev.name().unwrap_or("")))
+            && self.phys.as_deref().map_or(true, |p| dev.phys() == Some(p))
+    }
+}
+
+lazy_static! {
+    /// Loaded once at startup, before any devices are opened, so that
+    /// every `DeviceContext` can look up its alias when it's created.
+    static ref ALIASES: Mutex<Vec<Alias>> = Mutex::new(Vec::new());
+}
+
+/// Where we look for aliases if we weren't told: the XDG config
+/// directory, which is usually `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("evdotool").join("devices.toml"))
+}
+
+pub fn parse(s: &str) -> AliasResult<Vec<Alias>> {
+    let file: BTreeMap<String, AliasCriteria> = toml::from_str(s)?;
+    file.into_iter()
+        .map(|(alias, criteria)| Alias::new(alias, criteria))
+        .collect()
+}
+
+/// Load aliases from the given file, replacing any we had before.
+pub fn load(path: &Path) -> AliasResult<()> {
+    let s = std::fs::read_to_string(path).map_err(|e| AliasError::Read(path.to_path_buf(), e))?;
+    *ALIASES.lock().unwrap() = parse(&s)?;
+    Ok(())
+}
+
+/// The alphabetically first alias that matches the device, if any.
+pub fn alias_for(dev: &Device) -> Option<String> {
+    ALIASES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|alias| alias.matches(dev))
+        .map(|alias| alias.alias.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_aliases_in_alphabetical_order() {
+        let aliases = parse(
+            r#"
+            [right-joycon]
+            vendor_id = 0x057e
+            product_id = 0x2007
+
+            [left-joycon]
+            vendor_id = 0x057e
+            name = "Joy-Con \\(L\\)"
+            "#,
+        )
+        .unwrap();
+        let names: Vec<&str> = aliases.iter().map(|a| a.alias.as_str()).collect();
+        assert_eq!(names, ["left-joycon", "right-joycon"]);
+        assert_eq!(aliases[0].vendor_id, Some(0x057e));
+        assert_eq!(aliases[0].product_id, None);
+        assert!(aliases[0].name.as_ref().unwrap().is_match("Joy-Con (L)"));
+        assert_eq!(aliases[1].product_id, Some(0x2007));
+    }
+
+    #[test]
+    fn parse_empty_file() {
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_toml() {
+        assert!(matches!(
+            parse("[pad\nvendor_id = 1"),
+            Err(AliasError::Parse(_))
+        ));
+        assert!(matches!(
+            parse("pad = \"not a table\""),
+            Err(AliasError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_unknown_and_mistyped_fields() {
+        assert!(matches!(
+            parse("[pad]\nvendor = 1"),
+            Err(AliasError::Parse(_))
+        ));
+        assert!(matches!(
+            parse("[pad]\nvendor_id = \"057e\""),
+            Err(AliasError::Parse(_))
+        ));
+        assert!(matches!(
+            parse("[pad]\nvendor_id = 70000"),
+            Err(AliasError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_alias_without_criteria() {
+        assert!(matches!(
+            parse("[pad]"),
+            Err(AliasError::NoCriteria(alias)) if alias == "pad"
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_bad_regex() {
+        assert!(matches!(
+            parse("[pad]\nname = \"Joy-Con (L\""),
+            Err(AliasError::InvalidRegex(alias, _)) if alias == "pad"
+        ));
+    }
+}
@@ -1,71 +1,627 @@
-use crate::device;
-use crate::time_util;
-use anyhow::Context;
-use evdev_rs::enums::EventCode;
+use crate::coroutines;
+use crate::evdev_util::{self, CodeFromStrError};
+use crate::layers::{self, Layer};
+use crate::time_util::Time;
+use evdev_rs::enums::{EventCode, EventType};
+use evdev_rs::InputEvent;
+use rlua::prelude::{LuaContext, LuaError, LuaResult, LuaValue};
+use rlua::{FromLua, UserData, UserDataMethods};
+use std::cmp::Reverse;
 
 pub const BINDINGS_NAME: &str = "bindings";
 pub const IS_BOUND_KEY: &str = "DEVICE_IS_BOUND";
+pub const PATTERNS_KEY: &str = "DEVICE_PATTERNS";
+pub const ANY_KEY: &str = "*";
+pub const NEXT_BINDING_ID_NAME: &str = "next_binding_id";
+pub const GRAB_ON_BIND_NAME: &str = "grab_on_bind";
+
+/// The priority of bindings that have to see events before anything
+/// else does, so that they can hold them back or take them.
+pub const FIRST_PRIORITY: i64 = i64::MAX;
 
 use crate::DeviceContext;
 
-fn device_key(dev: &DeviceContext) -> rlua::Result<String> {
-    Ok(String::from(dev.friendly_name()?))
+#[derive(thiserror::Error, Debug)]
+pub enum BindError {
+    #[error(transparent)]
+    InvalidEventCode(#[from] CodeFromStrError),
+    #[error("pattern `{0}` does not match any event codes")]
+    EmptyPattern(String),
+    #[error(
+        "pattern `{0}` only matches EV_SYN codes, which patterns never get; bind them by name"
+    )]
+    SynOnlyPattern(String),
 }
 
-pub fn set_up_bindings(ctx: &rlua::Context) -> rlua::Result<()> {
-    let t = ctx.create_table()?;
-    for dev in device::DeviceContext::list_all(time_util::CLOCK)?.iter() {
-        let dev_name = device_key(dev)?;
+impl From<BindError> for rlua::Error {
+    fn from(e: BindError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+/// What a binding applies to. When several bindings could handle an
+/// event, they're called from most to least specific: an exact code,
+/// then patterns, then the event type, then `*`.
+#[derive(Clone, Debug)]
+pub enum BindTarget {
+    /// A single event code, e.g. `KEY_A`
+    Code(String),
+    /// A glob over event code names, e.g. `BTN_*`
+    Pattern(String),
+    /// Every code of an event type, e.g. `EV_KEY`
+    Type(EventType),
+    /// Every event other than EV_SYN
+    Any,
+}
+
+impl BindTarget {
+    pub fn parse(s: String) -> Result<Self, BindError> {
+        if s == ANY_KEY {
+            Ok(BindTarget::Any)
+        } else if evdev_util::is_glob(&s) {
+            let (syn, other): (Vec<EventCode>, Vec<EventCode>) = evdev_util::all_event_codes()
+                .filter(|code| evdev_util::glob_match(&s, &code.to_string()))
+                .partition(|code| evdev_util::event_type_of(code) == Some(EventType::EV_SYN));
+            if !other.is_empty() {
+                Ok(BindTarget::Pattern(s))
+            } else if !syn.is_empty() {
+                Err(BindError::SynOnlyPattern(s))
+            } else {
+                Err(BindError::EmptyPattern(s))
+            }
+        } else if let Some(event_type) = EventType::from_str(&s) {
+            Ok(BindTarget::Type(event_type))
+        } else {
+            Ok(BindTarget::Code(
+                evdev_util::event_code_from_str(s)?.to_string(),
+            ))
+        }
+    }
+
+    pub fn key(&self) -> String {
+        match self {
+            BindTarget::Code(code) => code.clone(),
+            BindTarget::Pattern(pattern) => pattern.clone(),
+            BindTarget::Type(event_type) => event_type.to_string(),
+            BindTarget::Any => String::from(ANY_KEY),
+        }
+    }
+}
+
+/// How specific a pattern is: the number of characters it matches
+/// literally. Patterns with more literal characters go first.
+fn pattern_specificity(pattern: &str) -> usize {
+    pattern.chars().filter(|&c| c != '*' && c != '?').count()
+}
+
+/// Options for a single binding, passed as the optional last argument
+/// to `bind`.
+#[derive(Clone, Debug, Default)]
+pub struct BindOptions {
+    /// Call the callback with a table describing the whole event
+    /// instead of just its value
+    pub full: bool,
+    /// Bindings with higher priorities are called first
+    pub priority: i64,
+    /// Watch events without handling them, so they still get passed
+    /// through if nothing else is bound to them
+    pub observe: bool,
+    /// The named layer the binding is in, or `None` for the base layer
+    pub layer: Option<String>,
+}
+
+impl<'lua> FromLua<'lua> for BindOptions {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::Nil => Ok(Self::default()),
+            LuaValue::Table(t) => Ok(Self {
+                full: t.get::<_, Option<bool>>("full")?.unwrap_or(false),
+                priority: t.get::<_, Option<i64>>("priority")?.unwrap_or(0),
+                observe: t.get::<_, Option<bool>>("observe")?.unwrap_or(false),
+                layer: match t.get::<_, Option<String>>("layer")? {
+                    Some(name) => Some(Layer::new(name)?.name().to_string()),
+                    None => None,
+                },
+            }),
+            _ => Err(LuaError::external("bind options must be a table")),
+        }
+    }
+}
+
+/// Bindings are kept per device identity rather than per friendly
+/// name, since identical devices have the same friendly name.
+pub fn device_key(dev: &DeviceContext) -> rlua::Result<String> {
+    Ok(String::from(dev.identity()))
+}
+
+/// Whether we've ever made a bindings table for a device with this key.
+pub fn is_known_device(ctx: &rlua::Context, dev_name: &str) -> rlua::Result<bool> {
+    let t = ctx.named_registry_value::<str, rlua::Table>(BINDINGS_NAME)?;
+    Ok(t.get::<_, Option<rlua::Table>>(dev_name)?.is_some())
+}
+
+pub fn set_up_bindings(ctx: &rlua::Context, grab_on_bind: bool) -> rlua::Result<()> {
+    ctx.set_named_registry_value(BINDINGS_NAME, ctx.create_table()?)?;
+    ctx.set_named_registry_value(GRAB_ON_BIND_NAME, grab_on_bind)?;
+    ctx.set_named_registry_value(NEXT_BINDING_ID_NAME, 1)?;
+    // Use the devices the script sees rather than listing them again,
+    // so that every one of them has a bindings table even if something
+    // was plugged in or out in between
+    let devices = ctx.globals().get::<_, rlua::Table>("DEVICES")?;
+    for dev_ud in devices.sequence_values::<rlua::AnyUserData>() {
+        add_device(ctx, &dev_ud?.borrow::<DeviceContext>()?)?;
+    }
+    Ok(())
+}
+
+/// Make sure there's a bindings table for the given device. Existing
+/// bindings are left alone so that they apply again when a device is
+/// plugged back in.
+pub fn add_device(ctx: &rlua::Context, dev: &DeviceContext) -> rlua::Result<()> {
+    let t = ctx.named_registry_value::<str, rlua::Table>(BINDINGS_NAME)?;
+    let dev_name = device_key(dev)?;
+    if t.get::<_, Option<rlua::Table>>(dev_name.clone())?.is_none() {
         let dev_table = ctx.create_table()?;
         dev_table.set(IS_BOUND_KEY, false)?;
+        dev_table.set(PATTERNS_KEY, ctx.create_table()?)?;
         t.set(dev_name, dev_table)?;
     }
-    ctx.set_named_registry_value(BINDINGS_NAME, t)?;
     Ok(())
 }
 
-pub fn device_has_bindings(ctx: &rlua::Context, dev: &DeviceContext) -> rlua::Result<bool> {
-    let t = ctx
-        .named_registry_value::<str, rlua::Table>(BINDINGS_NAME)
-        .with_context(|| "getting registry value")
-        .map_err(rlua::Error::external)?;
-    let dev_name = device_key(dev)?;
-    let dev_table = t
-        .get::<String, rlua::Table>(dev_name)
-        .with_context(|| "getting device table")
-        .map_err(rlua::Error::external)?;
-    dev_table.get(IS_BOUND_KEY)
+pub fn any_device_has_bindings(ctx: &rlua::Context) -> rlua::Result<bool> {
+    let t = ctx.named_registry_value::<str, rlua::Table>(BINDINGS_NAME)?;
+    for pair in t.pairs::<String, rlua::Table>() {
+        let (_, dev_table) = pair?;
+        if dev_table.get(IS_BOUND_KEY)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// A fresh id, to tell bindings apart and order them by when they
+/// were made.
+pub fn next_binding_id(ctx: &rlua::Context) -> rlua::Result<i64> {
+    let id = ctx.named_registry_value::<str, i64>(NEXT_BINDING_ID_NAME)?;
+    ctx.set_named_registry_value(NEXT_BINDING_ID_NAME, id + 1)?;
+    Ok(id)
 }
 
 pub fn set_in_bindings_table<'lua>(
     ctx: &rlua::Context<'lua>,
     dev_ud: &rlua::AnyUserData<'lua>,
-    event: &EventCode,
+    target: &BindTarget,
     callback: rlua::Function<'lua>,
-) -> rlua::Result<()> {
-    let s = event.to_string();
-    let t = ctx.named_registry_value::<&str, rlua::Table>(&BINDINGS_NAME)?;
-    let dev_name = device_key(&dev_ud.borrow::<DeviceContext>().unwrap())?;
-    let dev_table = t.get::<String, rlua::Table>(dev_name.clone())?;
-    dev_table.set(s, callback)?;
+    options: BindOptions,
+) -> rlua::Result<BindingHandle> {
+    let dev_name = device_key(&dev_ud.borrow::<DeviceContext>()?)?;
+    let dev_table = device_table(ctx, &dev_name)?;
+    let was_bound: bool = dev_table.get(IS_BOUND_KEY)?;
+    if !was_bound && ctx.named_registry_value::<str, bool>(GRAB_ON_BIND_NAME)? {
+        dev_ud.borrow_mut::<DeviceContext>()?.grab()?;
+    }
+    let id = next_binding_id(ctx)?;
+    let binding = ctx.create_table()?;
+    binding.set("callback", callback)?;
+    binding.set("full", options.full)?;
+    binding.set("priority", options.priority)?;
+    binding.set("observe", options.observe)?;
+    binding.set("layer", options.layer)?;
+    binding.set("id", id)?;
+    binding.set("bound", true)?;
+    entries_for(ctx, &dev_table, target)?.set(id, binding)?;
     dev_table.set(IS_BOUND_KEY, true)?;
-    t.set(dev_name, dev_table)?;
-    ctx.set_named_registry_value(BINDINGS_NAME, t)?;
-    Ok(())
+    Ok(BindingHandle {
+        device: dev_name,
+        target: target.clone(),
+        id,
+    })
+}
+
+fn device_table<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_name: &str,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let t = ctx.named_registry_value::<str, rlua::Table>(BINDINGS_NAME)?;
+    t.get::<_, rlua::Table>(dev_name)
+}
+
+fn parent_table<'lua>(
+    dev_table: &rlua::Table<'lua>,
+    target: &BindTarget,
+) -> rlua::Result<rlua::Table<'lua>> {
+    match target {
+        BindTarget::Pattern(_) => dev_table.get::<_, rlua::Table>(PATTERNS_KEY),
+        _ => Ok(dev_table.clone()),
+    }
+}
+
+/// The bindings for one target on one device, keyed by binding id, if
+/// there have ever been any.
+fn existing_entries<'lua>(
+    dev_table: &rlua::Table<'lua>,
+    target: &BindTarget,
+) -> rlua::Result<Option<rlua::Table<'lua>>> {
+    parent_table(dev_table, target)?.get(target.key())
+}
+
+/// The bindings for one target on one device, keyed by binding id.
+fn entries_for<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_table: &rlua::Table<'lua>,
+    target: &BindTarget,
+) -> rlua::Result<rlua::Table<'lua>> {
+    match existing_entries(dev_table, target)? {
+        Some(entries) => Ok(entries),
+        None => {
+            let entries = ctx.create_table()?;
+            parent_table(dev_table, target)?.set(target.key(), entries.clone())?;
+            Ok(entries)
+        }
+    }
+}
+
+/// Where a binding comes in the order bindings are called in: see
+/// `matching_bindings`. Earlier bindings sort first.
+pub type BindingOrder = (Reverse<i64>, Reverse<(u8, usize)>, i64);
+
+/// Returned by `bind` so that the binding can be removed later.
+#[derive(Clone, Debug)]
+pub struct BindingHandle {
+    device: String,
+    target: BindTarget,
+    id: i64,
+}
+
+impl BindingHandle {
+    fn binding<'lua>(&self, ctx: &rlua::Context<'lua>) -> rlua::Result<Option<rlua::Table<'lua>>> {
+        let dev_table = device_table(ctx, &self.device)?;
+        match existing_entries(&dev_table, &self.target)? {
+            Some(entries) => entries.get(self.id),
+            None => Ok(None),
+        }
+    }
+
+    /// Where the binding comes in dispatch order, given the priority it
+    /// was bound with.
+    pub fn order(&self, priority: i64) -> BindingOrder {
+        (
+            Reverse(priority),
+            Reverse(specificity(&self.target)),
+            self.id,
+        )
+    }
+
+    /// Remove the binding. Returns whether it was still bound.
+    pub fn unbind(&self, ctx: &rlua::Context) -> rlua::Result<bool> {
+        let binding = match self.binding(ctx)? {
+            Some(binding) => binding,
+            None => return Ok(false),
+        };
+        // Mark it as well as removing it, in case it's partway through
+        // being dispatched to
+        binding.set("bound", false)?;
+        let dev_table = device_table(ctx, &self.device)?;
+        entries_for(ctx, &dev_table, &self.target)?.set(self.id, rlua::Value::Nil)?;
+        Ok(true)
+    }
+}
+
+/// Unbind every handle in a sequence of them. Returns whether any
+/// were still bound.
+pub fn unbind_all(ctx: &rlua::Context, handles: rlua::Table) -> rlua::Result<bool> {
+    let mut was_bound = false;
+    for handle in handles.sequence_values::<rlua::AnyUserData>() {
+        was_bound |= handle?.borrow::<BindingHandle>()?.unbind(ctx)?;
+    }
+    Ok(was_bound)
+}
+
+impl UserData for BindingHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("unbind", |ctx, this, ()| this.unbind(&ctx));
+        methods.add_method("is_bound", |ctx, this, ()| {
+            Ok(this.binding(&ctx)?.is_some())
+        });
+        methods.add_method("target", |_, this, ()| Ok(this.target.key()));
+    }
 }
 
-pub fn get_in_bindings_map<'lua>(
+/// How specific a binding's target is, for ordering bindings with the
+/// same priority: exact codes, then patterns by how many characters
+/// they match literally, then event types, then `*`.
+fn specificity(target: &BindTarget) -> (u8, usize) {
+    match target {
+        BindTarget::Code(_) => (3, 0),
+        BindTarget::Pattern(pattern) => (2, pattern_specificity(pattern)),
+        BindTarget::Type(_) => (1, 0),
+        BindTarget::Any => (0, 0),
+    }
+}
+
+/// Every binding that applies to the event, in the order they should
+/// be called: highest priority first, then most specific, then oldest.
+pub fn matching_bindings<'lua>(
     ctx: &rlua::Context<'lua>,
     dev: &DeviceContext,
     event: &EventCode,
-) -> rlua::Result<Option<rlua::Function<'lua>>> {
+) -> rlua::Result<Vec<(BindingOrder, rlua::Table<'lua>)>> {
     let s = event.to_string();
-    let t = ctx.named_registry_value::<&str, rlua::Table>(&BINDINGS_NAME)?;
-    let dev_name = device_key(dev)?;
-    let dev_table = t.get::<String, rlua::Table>(dev_name)?;
-    match dev_table.get::<_, rlua::Value>(s)? {
-        rlua::Value::Nil => Ok(None),
-        rlua::Value::Function(f) => Ok(Some(f)),
-        other => panic!("bindings table contained the wrong thing: `{:?}`", other),
+    let dev_table = device_table(ctx, &device_key(dev)?)?;
+    let event_type = evdev_util::event_type_of(event);
+
+    let mut targets = vec![BindTarget::Code(s.clone())];
+    // EV_SYN is only ever handled by bindings that ask for it by
+    // name, since swallowing it would break pass-through
+    if event_type == Some(EventType::EV_SYN) {
+        targets.push(BindTarget::Type(EventType::EV_SYN));
+    } else {
+        for pair in dev_table
+            .get::<_, rlua::Table>(PATTERNS_KEY)?
+            .pairs::<String, rlua::Table>()
+        {
+            let (pattern, _) = pair?;
+            if evdev_util::glob_match(&pattern, &s) {
+                targets.push(BindTarget::Pattern(pattern));
+            }
+        }
+        if let Some(event_type) = event_type {
+            targets.push(BindTarget::Type(event_type));
+        }
+        targets.push(BindTarget::Any);
+    }
+
+    let mut found = Vec::new();
+    for target in targets.iter() {
+        let entries = match existing_entries(&dev_table, target)? {
+            Some(entries) => entries,
+            None => continue,
+        };
+        let key = specificity(target);
+        for pair in entries.pairs::<i64, rlua::Table>() {
+            let (id, binding) = pair?;
+            let priority: i64 = binding.get("priority")?;
+            found.push(((Reverse(priority), Reverse(key), id), binding));
+        }
+    }
+    found.sort_by_key(|(order, _)| *order);
+    Ok(found)
+}
+
+/// Describe an event to Lua: its value, code, type, kernel timestamp
+/// and the device it came from.
+pub fn event_table<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    input: &InputEvent,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let t = ctx.create_table()?;
+    t.set("value", input.value)?;
+    t.set("code", input.event_code.to_string())?;
+    t.set(
+        "type",
+        evdev_util::event_type_of(&input.event_code).map(|et| et.to_string()),
+    )?;
+    t.set("time", Time::from(input.time))?;
+    t.set("device", dev_ud.clone())?;
+    Ok(t)
+}
+
+/// The highest active layer with a binding for an event that isn't
+/// just observing.
+fn top_layer(
+    ctx: &rlua::Context,
+    bindings: &[(BindingOrder, rlua::Table)],
+) -> rlua::Result<Option<String>> {
+    let mut top: Option<(usize, Option<String>)> = None;
+    for (_, binding) in bindings {
+        if binding.get::<_, bool>("observe")? {
+            continue;
+        }
+        let layer: Option<String> = binding.get("layer")?;
+        if let Some(rank) = layers::rank(ctx, layer.as_deref())? {
+            if top.as_ref().map_or(true, |(top_rank, _)| rank > *top_rank) {
+                top = Some((rank, layer));
+            }
+        }
+    }
+    Ok(top.and_then(|(_, layer)| layer))
+}
+
+/// The layer that should get an event: the one its key's press went
+/// to if it's a release or a repeat, otherwise the top layer for it.
+fn choose_layer(
+    ctx: &rlua::Context,
+    dev_name: &str,
+    input: &InputEvent,
+    bindings: &[(BindingOrder, rlua::Table)],
+) -> rlua::Result<Option<String>> {
+    let is_key = evdev_util::event_type_of(&input.event_code) == Some(EventType::EV_KEY);
+    let code = input.event_code.to_string();
+    if is_key && input.value != 1 {
+        if let Some(layer) = layers::held_layer(ctx, dev_name, &code)? {
+            if input.value == 0 {
+                layers::set_held_layer(ctx, dev_name, &code, None)?;
+            }
+            return Ok(layer);
+        }
+    }
+    let layer = top_layer(ctx, bindings)?;
+    if is_key && input.value == 1 {
+        layers::set_held_layer(ctx, dev_name, &code, Some(layer.clone()))?;
+    }
+    Ok(layer)
+}
+
+/// The layer a replayed event went to the first time round. Nothing is
+/// recorded, since that already happened when it was first dispatched.
+fn replayed_layer(
+    ctx: &rlua::Context,
+    dev_name: &str,
+    input: &InputEvent,
+    bindings: &[(BindingOrder, rlua::Table)],
+) -> rlua::Result<Option<String>> {
+    match layers::held_layer(ctx, dev_name, &input.event_code.to_string())? {
+        Some(layer) => Ok(layer),
+        None => top_layer(ctx, bindings),
+    }
+}
+
+/// Call the callbacks bound to the given event, stopping early if
+/// one of them returns `true`. Only the bindings in one layer handle
+/// the event (see `choose_layer`); lower layers only see it if the
+/// higher ones have nothing bound to it. Observing bindings see it
+/// from any active layer. Returns whether the event was handled, i.e.
+/// whether any binding that isn't just observing was called, or some
+/// callback returned `true`.
+pub fn dispatch<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    input: &InputEvent,
+) -> rlua::Result<bool> {
+    dispatch_after(ctx, dev_ud, input, None)
+}
+
+/// Dispatch an event, only calling the bindings that come after
+/// `after` if it's given. Those are the ones that didn't see the event
+/// the first time round when it's being replayed, so the layer it went
+/// to is reused and one-shot layers aren't ended again. The earlier
+/// bindings still count towards whether it was handled.
+fn dispatch_after<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    input: &InputEvent,
+    after: Option<BindingOrder>,
+) -> rlua::Result<bool> {
+    let (dev_name, bindings) = {
+        let dev = dev_ud.borrow::<DeviceContext>()?;
+        (
+            device_key(&dev)?,
+            matching_bindings(ctx, &dev, &input.event_code)?,
+        )
+    };
+    let layer = match after {
+        Some(_) => replayed_layer(ctx, &dev_name, input, &bindings)?,
+        None => choose_layer(ctx, &dev_name, input, &bindings)?,
+    };
+    let is_press =
+        evdev_util::event_type_of(&input.event_code) == Some(EventType::EV_KEY) && input.value == 1;
+    let one_shots = if is_press && after.is_none() {
+        layers::pending_one_shots(ctx)?
+    } else {
+        Vec::new()
+    };
+    let mut handled = false;
+    for (order, binding) in bindings {
+        // An earlier callback may have unbound this one
+        if !binding.get::<_, bool>("bound")? {
+            continue;
+        }
+        let observe = binding.get::<_, bool>("observe")?;
+        let binding_layer: Option<String> = binding.get("layer")?;
+        let in_layer = if observe {
+            layers::rank(ctx, binding_layer.as_deref())?.is_some()
+        } else {
+            binding_layer == layer
+        };
+        if !in_layer {
+            continue;
+        }
+        handled |= !observe;
+        if after.map_or(false, |after| order <= after) {
+            continue;
+        }
+        let callback = binding.get::<_, rlua::Function>("callback")?;
+        let result = if binding.get::<_, bool>("full")? {
+            coroutines::spawn(ctx, callback, event_table(ctx, dev_ud, input)?)?
+        } else {
+            coroutines::spawn(ctx, callback, input.value)?
+        };
+        if let rlua::Value::Boolean(true) = result {
+            handled = true;
+            break;
+        }
+    }
+    layers::end_one_shots(ctx, &one_shots)?;
+    Ok(handled)
+}
+
+/// Dispatch the event, and pass it through if nothing handled it.
+pub fn dispatch_or_forward<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    input: &InputEvent,
+) -> rlua::Result<()> {
+    if !dispatch(ctx, dev_ud, input)? {
+        dev_ud.borrow::<DeviceContext>()?.forward(input)?;
+    }
+    Ok(())
+}
+
+/// Carry on dispatching an event that a binding held back, starting
+/// after that binding, and pass it through if nothing handled it.
+pub fn replay_after<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    input: &InputEvent,
+    after: BindingOrder,
+) -> rlua::Result<()> {
+    if !dispatch_after(ctx, dev_ud, input, Some(after))? {
+        dev_ud.borrow::<DeviceContext>()?.forward(input)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<BindTarget, BindError> {
+        BindTarget::parse(String::from(s))
+    }
+
+    #[test]
+    fn parse_kinds_of_target() {
+        assert!(matches!(parse("KEY_A"), Ok(BindTarget::Code(code)) if code == "KEY_A"));
+        assert!(matches!(parse("BTN_*"), Ok(BindTarget::Pattern(p)) if p == "BTN_*"));
+        assert!(matches!(
+            parse("EV_KEY"),
+            Ok(BindTarget::Type(EventType::EV_KEY))
+        ));
+        assert!(matches!(parse("*"), Ok(BindTarget::Any)));
+    }
+
+    #[test]
+    fn parse_rejects_bad_codes_and_patterns() {
+        assert!(matches!(
+            parse("KEY_NOPE"),
+            Err(BindError::InvalidEventCode(_))
+        ));
+        assert!(matches!(parse("NOPE_*"), Err(BindError::EmptyPattern(_))));
+        assert!(matches!(parse("SYN_*"), Err(BindError::SynOnlyPattern(_))));
+    }
+
+    #[test]
+    fn pattern_matching_syn_and_more_is_fine() {
+        // SW_* codes match too, so this isn't only EV_SYN
+        assert!(matches!(parse("S*"), Ok(BindTarget::Pattern(_))));
+    }
+
+    #[test]
+    fn more_literal_patterns_are_more_specific() {
+        assert!(
+            specificity(&BindTarget::Code(String::from("KEY_A")))
+                > specificity(&BindTarget::Pattern(String::from("KEY_?")))
+        );
+        assert!(
+            specificity(&BindTarget::Pattern(String::from("KEY_?")))
+                > specificity(&BindTarget::Pattern(String::from("KEY_*")))
+        );
+        assert!(
+            specificity(&BindTarget::Pattern(String::from("*")))
+                > specificity(&BindTarget::Type(EventType::EV_KEY))
+        );
+        assert!(specificity(&BindTarget::Type(EventType::EV_KEY)) > specificity(&BindTarget::Any));
     }
 }
@@ -0,0 +1,346 @@
+use crate::bindings::{self, BindOptions, BindTarget, BindingHandle};
+use crate::coroutines;
+use crate::device::DeviceContext;
+use crate::evdev_util::{self, CodeFromStrError};
+use crate::time_util::Time;
+use crate::timers;
+use evdev_rs::enums::EventType;
+use evdev_rs::{InputEvent, TimeVal};
+use rlua::prelude::{LuaContext, LuaError, LuaResult, LuaValue};
+use rlua::{FromLua, UserData, UserDataMethods};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChordError {
+    #[error("chords can only be made of keys and buttons, not `{0}`")]
+    NotAKey(String),
+    #[error("a chord needs at least two keys")]
+    TooFewMembers,
+    #[error("chord members must be {{device, code}} pairs")]
+    InvalidMember,
+    #[error(transparent)]
+    InvalidEventCode(#[from] CodeFromStrError),
+}
+
+impl From<ChordError> for rlua::Error {
+    fn from(e: ChordError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+const DEFAULT_WINDOW: f64 = 0.05;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ChordOptions {
+    /// Longest time, in seconds, between the first and last press
+    pub window: f64,
+    pub priority: i64,
+}
+
+impl Default for ChordOptions {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_WINDOW,
+            priority: bindings::FIRST_PRIORITY,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for ChordOptions {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        let defaults = Self::default();
+        match lua_value {
+            LuaValue::Nil => Ok(defaults),
+            LuaValue::Table(t) => Ok(Self {
+                window: t
+                    .get::<_, Option<f64>>("window")?
+                    .unwrap_or(defaults.window),
+                priority: t
+                    .get::<_, Option<i64>>("priority")?
+                    .unwrap_or(defaults.priority),
+            }),
+            _ => Err(LuaError::external("chord options must be a table")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    Idle,
+    /// Some of the keys are down and we're holding their presses back
+    /// until we know whether the rest will follow
+    Pending {
+        since: f64,
+        pressed: Vec<bool>,
+    },
+    /// Every key went down in time and the callback has been told
+    Active {
+        pressed: Vec<bool>,
+    },
+}
+
+/// Watches for several keys, possibly on several devices, being
+/// pressed together. Presses that turn out not to be part of the chord
+/// are replayed to the bindings that come after the chord's. It keeps
+/// its callback, the held-back events and the bindings that feed events
+/// in alongside it (see [`timers::start_from`]).
+#[derive(Debug)]
+pub struct Chord {
+    window: f64,
+    size: usize,
+    /// The priority the chord's own bindings were made with
+    priority: i64,
+    state: State,
+    /// Bumped every time a chord starts
+    generation: u64,
+}
+
+impl UserData for Chord {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function("unbind", |ctx, chord_ud: rlua::AnyUserData| {
+            let handles = chord_ud.get_user_value::<rlua::Table>()?.get("bindings")?;
+            bindings::unbind_all(&ctx, handles)
+        });
+    }
+}
+
+fn call_back(ctx: &rlua::Context, chord_ud: &rlua::AnyUserData, value: i32) -> rlua::Result<()> {
+    let callback = chord_ud
+        .get_user_value::<rlua::Table>()?
+        .get::<_, rlua::Function>("callback")?;
+    coroutines::spawn(ctx, callback, value)?;
+    Ok(())
+}
+
+fn input_from_event_table(event: &rlua::Table) -> rlua::Result<InputEvent> {
+    let code = evdev_util::event_code_from_str(event.get("code")?).map_err(ChordError::from)?;
+    let time = TimeVal::from(event.get::<_, Time>("time")?);
+    Ok(InputEvent::new(&time, &code, event.get("value")?))
+}
+
+/// The chord isn't happening. Replay everything we held back, in order,
+/// to the bindings that would have seen it next if we'd let it through.
+fn give_up(ctx: &rlua::Context, chord_ud: &rlua::AnyUserData) -> rlua::Result<()> {
+    let user_value = chord_ud.get_user_value::<rlua::Table>()?;
+    let held = user_value.get::<_, rlua::Table>("held")?;
+    let handles = user_value.get::<_, rlua::Table>("bindings")?;
+    user_value.set("held", ctx.create_table()?)?;
+    let priority = {
+        let mut chord = chord_ud.borrow_mut::<Chord>()?;
+        chord.state = State::Idle;
+        chord.priority
+    };
+    for entry in held.sequence_values::<rlua::Table>() {
+        let entry = entry?;
+        let index: usize = entry.get("index")?;
+        let event = entry.get::<_, rlua::Table>("event")?;
+        let after = handles
+            .get::<_, rlua::AnyUserData>(index + 1)?
+            .borrow::<BindingHandle>()?
+            .order(priority);
+        let dev_ud = event.get::<_, rlua::AnyUserData>("device")?;
+        bindings::replay_after(ctx, &dev_ud, &input_from_event_table(&event)?, after)?;
+    }
+    Ok(())
+}
+
+/// Keep an event from the given member back until we know whether
+/// the chord is happening.
+fn hold_back(
+    ctx: &rlua::Context,
+    chord_ud: &rlua::AnyUserData,
+    index: usize,
+    event: rlua::Table,
+) -> rlua::Result<()> {
+    let held = chord_ud
+        .get_user_value::<rlua::Table>()?
+        .get::<_, rlua::Table>("held")?;
+    let entry = ctx.create_table()?;
+    entry.set("index", index)?;
+    entry.set("event", event)?;
+    held.set(held.raw_len() + 1, entry)
+}
+
+/// Start a new attempt at the chord with the given key.
+fn start<'lua>(
+    ctx: &rlua::Context<'lua>,
+    chord_ud: &rlua::AnyUserData<'lua>,
+    index: usize,
+    time: f64,
+) -> rlua::Result<()> {
+    let (generation, window) = {
+        let mut chord = chord_ud.borrow_mut::<Chord>()?;
+        let mut pressed = vec![false; chord.size];
+        pressed[index] = true;
+        chord.state = State::Pending {
+            since: time,
+            pressed,
+        };
+        chord.generation += 1;
+        (chord.generation, chord.window)
+    };
+    let on_timeout = ctx
+        .create_function(handle_timeout)?
+        .bind((chord_ud.clone(), generation))?;
+    timers::start_from(ctx, time, window, rlua::Value::Function(on_timeout))?;
+    Ok(())
+}
+
+/// Feed an event from one of the chord's keys in. Returns `true` if
+/// the chord has taken the event, or nothing to let it through.
+fn handle_event<'lua>(
+    ctx: rlua::Context<'lua>,
+    (chord_ud, index, event): (rlua::AnyUserData<'lua>, usize, rlua::Table<'lua>),
+) -> rlua::Result<Option<bool>> {
+    let value: i32 = event.get("value")?;
+    let time: f64 = event.get("time")?;
+    let (state, window) = {
+        let chord = chord_ud.borrow::<Chord>()?;
+        (chord.state.clone(), chord.window)
+    };
+    match (state, value) {
+        (State::Idle, 1) => {
+            start(&ctx, &chord_ud, index, time)?;
+            hold_back(&ctx, &chord_ud, index, event)?;
+        }
+        (State::Idle, _) => return Ok(None),
+        (State::Pending { since, .. }, 1) if time - since > window => {
+            // Too late for the last attempt, but this could be the start
+            // of another one
+            give_up(&ctx, &chord_ud)?;
+            start(&ctx, &chord_ud, index, time)?;
+            hold_back(&ctx, &chord_ud, index, event)?;
+        }
+        (State::Pending { mut pressed, since }, 1) => {
+            hold_back(&ctx, &chord_ud, index, event)?;
+            pressed[index] = true;
+            if pressed.iter().all(|p| *p) {
+                chord_ud.borrow_mut::<Chord>()?.state = State::Active { pressed };
+                chord_ud
+                    .get_user_value::<rlua::Table>()?
+                    .set("held", ctx.create_table()?)?;
+                call_back(&ctx, &chord_ud, 1)?;
+            } else {
+                chord_ud.borrow_mut::<Chord>()?.state = State::Pending { since, pressed };
+            }
+        }
+        (State::Pending { pressed, .. }, 0) => {
+            if !pressed[index] {
+                return Ok(None);
+            }
+            // Let go before the chord was complete, so it was just a
+            // press after all. The release goes through after the
+            // replayed press.
+            give_up(&ctx, &chord_ud)?;
+            return Ok(None);
+        }
+        (State::Pending { pressed, .. }, _) => {
+            // Hold back autorepeat along with the press it's repeating
+            if !pressed[index] {
+                return Ok(None);
+            }
+            hold_back(&ctx, &chord_ud, index, event)?;
+        }
+        (State::Active { mut pressed }, 0) => {
+            if !pressed[index] {
+                return Ok(None);
+            }
+            let was_complete = pressed.iter().all(|p| *p);
+            pressed[index] = false;
+            // The chord ends when the first of its keys is let go, but
+            // the rest of the releases are still the chord's
+            if was_complete {
+                call_back(&ctx, &chord_ud, 0)?;
+            }
+            chord_ud.borrow_mut::<Chord>()?.state = if pressed.iter().any(|p| *p) {
+                State::Active { pressed }
+            } else {
+                State::Idle
+            };
+        }
+        (State::Active { pressed }, _) => {
+            if !pressed[index] {
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(true))
+}
+
+fn handle_timeout<'lua>(
+    ctx: rlua::Context<'lua>,
+    (chord_ud, generation): (rlua::AnyUserData<'lua>, u64),
+) -> rlua::Result<()> {
+    let expired = {
+        let chord = chord_ud.borrow::<Chord>()?;
+        matches!(chord.state, State::Pending { .. }) && chord.generation == generation
+    };
+    if expired {
+        give_up(&ctx, &chord_ud)?;
+    }
+    Ok(())
+}
+
+/// Start watching for a chord. `members` is a sequence of `{device,
+/// code}` pairs.
+pub fn bind<'lua>(
+    ctx: &rlua::Context<'lua>,
+    members: Vec<rlua::Table<'lua>>,
+    callback: rlua::Function<'lua>,
+    options: ChordOptions,
+) -> rlua::Result<rlua::AnyUserData<'lua>> {
+    if members.len() < 2 {
+        return Err(ChordError::TooFewMembers.into());
+    }
+    let mut keys = Vec::new();
+    for member in members {
+        let dev_ud = member
+            .get::<_, rlua::AnyUserData>(1)
+            .map_err(|_| ChordError::InvalidMember)?;
+        if !dev_ud.is::<DeviceContext>() {
+            return Err(ChordError::InvalidMember.into());
+        }
+        let code = member
+            .get::<_, String>(2)
+            .map_err(|_| ChordError::InvalidMember)?;
+        let event_code = evdev_util::event_code_from_str(code.clone()).map_err(ChordError::from)?;
+        if evdev_util::event_type_of(&event_code) != Some(EventType::EV_KEY) {
+            return Err(ChordError::NotAKey(code).into());
+        }
+        keys.push((dev_ud, event_code.to_string()));
+    }
+
+    let chord_ud = ctx.create_userdata(Chord {
+        window: options.window,
+        size: keys.len(),
+        priority: options.priority,
+        state: State::Idle,
+        generation: 0,
+    })?;
+    let user_value = ctx.create_table()?;
+    user_value.set("callback", callback)?;
+    user_value.set("held", ctx.create_table()?)?;
+    chord_ud.set_user_value(user_value.clone())?;
+
+    let mut handles = Vec::new();
+    for (index, (dev_ud, code)) in keys.into_iter().enumerate() {
+        let on_event = ctx
+            .create_function(handle_event)?
+            .bind((chord_ud.clone(), index))?;
+        // Observing, so that the events the chord lets through are
+        // passed on as usual if nothing else is bound to them
+        handles.push(bindings::set_in_bindings_table(
+            ctx,
+            &dev_ud,
+            &BindTarget::Code(code),
+            on_event,
+            BindOptions {
+                full: true,
+                priority: options.priority,
+                observe: true,
+                layer: None,
+            },
+        )?);
+    }
+    user_value.set("bindings", handles)?;
+    Ok(chord_ud)
+}
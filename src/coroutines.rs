@@ -0,0 +1,134 @@
+use crate::bindings;
+use crate::device::DeviceContext;
+use crate::evdev_util;
+use crate::timers;
+use evdev_rs::InputEvent;
+use rlua::FromLua;
+
+// These have to match what lua/sleep.lua yields
+pub const SLEEP_MARKER: &str = "evdotool.sleep";
+pub const WAIT_FOR_EVENT_MARKER: &str = "evdotool.wait_for_event";
+
+pub const WAITERS_NAME: &str = "waiters";
+
+pub fn set_up_coroutines(ctx: &rlua::Context) -> rlua::Result<()> {
+    ctx.set_named_registry_value(WAITERS_NAME, ctx.create_table()?)
+}
+
+/// Add a hook to the list of them kept in the named registry value.
+pub fn add_hook<'lua>(
+    ctx: &rlua::Context<'lua>,
+    hooks_name: &str,
+    hook: rlua::Function<'lua>,
+) -> rlua::Result<()> {
+    let hooks = ctx.named_registry_value::<str, rlua::Table>(hooks_name)?;
+    hooks.set(hooks.len()? + 1, hook)
+}
+
+/// Spawn every hook in the named list with the same arguments, in the
+/// order they were added.
+pub fn run_hooks<'lua, A: rlua::ToLuaMulti<'lua> + Clone>(
+    ctx: &rlua::Context<'lua>,
+    hooks_name: &str,
+    args: A,
+) -> rlua::Result<()> {
+    let hooks = ctx.named_registry_value::<str, rlua::Table>(hooks_name)?;
+    for hook in hooks.sequence_values::<rlua::Function>() {
+        spawn(ctx, hook?, args.clone())?;
+    }
+    Ok(())
+}
+
+/// Run a callback as a coroutine, so that it can `sleep` or
+/// `wait_for_event` without holding up everything else. Returns what
+/// the callback returned, or nil if it's still suspended.
+pub fn spawn<'lua, A: rlua::ToLuaMulti<'lua>>(
+    ctx: &rlua::Context<'lua>,
+    callback: rlua::Function<'lua>,
+    args: A,
+) -> rlua::Result<rlua::Value<'lua>> {
+    resume(ctx, ctx.create_thread(callback)?, args)
+}
+
+/// Continue a suspended callback and arrange for it to be resumed
+/// again if it suspends itself once more.
+pub fn resume<'lua, A: rlua::ToLuaMulti<'lua>>(
+    ctx: &rlua::Context<'lua>,
+    thread: rlua::Thread<'lua>,
+    args: A,
+) -> rlua::Result<rlua::Value<'lua>> {
+    let mut results = thread.resume::<_, rlua::MultiValue>(args)?.into_iter();
+    if thread.status() != rlua::ThreadStatus::Resumable {
+        return Ok(results.next().unwrap_or(rlua::Value::Nil));
+    }
+
+    let marker = match results.next() {
+        Some(rlua::Value::String(s)) => String::from(s.to_str()?),
+        _ => String::new(),
+    };
+    let mut arg = || results.next().unwrap_or(rlua::Value::Nil);
+    if marker == SLEEP_MARKER {
+        let seconds = f64::from_lua(arg(), *ctx)?;
+        timers::start(ctx, seconds, None, rlua::Value::Thread(thread))?;
+    } else if marker == WAIT_FOR_EVENT_MARKER {
+        let dev_ud = rlua::AnyUserData::from_lua(arg(), *ctx)?;
+        let code = String::from_lua(arg(), *ctx)?;
+        // Check the code now rather than waiting forever for an event
+        // that can't happen
+        evdev_util::event_code_from_str(code.clone()).map_err(rlua::Error::external)?;
+        let waiter = ctx.create_table()?;
+        waiter.set("device", dev_ud)?;
+        waiter.set("code", code)?;
+        waiter.set("thread", thread)?;
+        let waiters = ctx.named_registry_value::<str, rlua::Table>(WAITERS_NAME)?;
+        waiters.set(waiters.len()? + 1, waiter)?;
+    } else {
+        return Err(rlua::Error::RuntimeError(String::from(
+            "callbacks may only yield by calling sleep or wait_for_event",
+        )));
+    }
+    Ok(rlua::Value::Nil)
+}
+
+/// Resume every callback that's waiting for this event.
+pub fn wake_waiters<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    input: &InputEvent,
+) -> rlua::Result<()> {
+    let waiters = ctx.named_registry_value::<str, rlua::Table>(WAITERS_NAME)?;
+    if waiters.len()? == 0 {
+        return Ok(());
+    }
+    let dev_name = bindings::device_key(&dev_ud.borrow::<DeviceContext>()?)?;
+    let code = input.event_code.to_string();
+
+    let mut woken = Vec::new();
+    let mut remaining = Vec::new();
+    for waiter in waiters.clone().sequence_values::<rlua::Table>() {
+        let waiter = waiter?;
+        let waiter_dev = waiter.get::<_, rlua::AnyUserData>("device")?;
+        let waiter_dev_name = bindings::device_key(&waiter_dev.borrow::<DeviceContext>()?)?;
+        if waiter_dev_name == dev_name && waiter.get::<_, String>("code")? == code {
+            woken.push(waiter.get::<_, rlua::Thread>("thread")?);
+        } else {
+            remaining.push(waiter);
+        }
+    }
+    if woken.is_empty() {
+        return Ok(());
+    }
+
+    // Update the table before resuming anything, since resumed
+    // callbacks may start waiting again
+    for i in 1..=waiters.len()? {
+        waiters.set(i, rlua::Value::Nil)?;
+    }
+    for (i, waiter) in remaining.into_iter().enumerate() {
+        waiters.set(i + 1, waiter)?;
+    }
+    for thread in woken.into_iter() {
+        resume(ctx, thread, input.value)?;
+    }
+    Ok(())
+}
@@ -1,10 +1,14 @@
+use crate::aliases;
+use crate::device_kind::{self, DeviceKind};
 use crate::evdev_util;
-use crate::friendly_name::friendly_name;
-use evdev_rs::enums::{EventCode, EV_ABS};
-use evdev_rs::{Device, DeviceWrapper, InputEvent};
+use crate::friendly_name::{friendly_name, friendly_name_words};
+use evdev_rs::enums::{EventCode, EventType, InputProp, EV_ABS};
+use evdev_rs::{Device, DeviceWrapper, GrabMode, InputEvent, UInputDevice};
 use rlua::{ToLua, UserData, UserDataMethods};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use std::str::FromStr;
 
@@ -12,6 +16,7 @@ use std::str::FromStr;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use crate::time_util::Clock;
+use crate::virtual_input;
 
 #[derive(thiserror::Error, Debug)]
 pub enum DeviceError {
@@ -31,9 +36,86 @@ impl From<DeviceError> for rlua::Error {
 
 pub type DeviceResult<T> = Result<T, DeviceError>;
 
+/// A friendly name that's already in use, and what it was made from.
+#[derive(Clone, Debug)]
+pub struct NameClaim {
+    /// None if the device hasn't been given a name yet
+    name: Option<String>,
+    aliased: bool,
+    base: &'static str,
+    hash: u64,
+}
+
+/// See [`DeviceContext::choose_friendly_name`]. `two_words` is the
+/// device's two-word name, for when another kind of device has the same
+/// first word.
+fn pick_friendly_name(
+    alias: Option<&str>,
+    base: &'static str,
+    hash: u64,
+    two_words: String,
+    others: &[NameClaim],
+) -> String {
+    let stem = if let Some(alias) = alias {
+        String::from(alias)
+    } else if others
+        .iter()
+        .any(|o| !o.aliased && o.base == base && o.hash != hash)
+    {
+        two_words
+    } else {
+        String::from(base)
+    };
+    let taken = |name: &str| others.iter().any(|o| o.name.as_deref() == Some(name));
+    let mut name = stem.clone();
+    let mut n = 1;
+    while taken(&name) {
+        n += 1;
+        name = format!("{}-{}", stem, n);
+    }
+    name
+}
+
+/// The N in `/dev/input/eventN`, if the path looks like that.
+fn node_index(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("event")?
+        .parse()
+        .ok()
+}
+
+/// What identical devices are numbered by: the port they're plugged
+/// into, then their device node, numerically so that event10 comes
+/// after event9.
+fn port_order<'a>(
+    phys: Option<&'a str>,
+    path: &'a Path,
+) -> (Option<&'a str>, Option<u32>, &'a Path) {
+    (phys, node_index(path), path)
+}
+
 #[derive(Debug)]
 pub struct DeviceContext {
     dev: Device,
+    path: PathBuf,
+    /// Tells this device apart from every other device for as long as
+    /// the script runs, even identical ones: the device node and phys
+    /// path it was first opened with. Kept when the device is unplugged
+    /// and plugged back in.
+    identity: String,
+    /// From the user's aliases file, if any of them match
+    alias: Option<String>,
+    /// Usually just the alias or the word picked by hashing the
+    /// device, but see [`DeviceContext::choose_friendly_name`]
+    friendly_name: String,
+    grabbed: bool,
+    /// Virtual clone of this device that unbound events are forwarded
+    /// to, if pass-through is enabled
+    passthrough: Option<UInputDevice>,
+    /// Whether the grab was only taken for pass-through, so that
+    /// turning pass-through off should give it back
+    passthrough_grabbed: bool,
 }
 
 impl Hash for DeviceContext {
@@ -46,12 +128,130 @@ impl Hash for DeviceContext {
 }
 
 impl DeviceContext {
-    pub fn new(dev: Device) -> Self {
-        Self { dev }
+    pub fn new(dev: Device, path: PathBuf) -> Self {
+        let identity = format!("{}:{}", path.display(), dev.phys().unwrap_or(""));
+        let alias = aliases::alias_for(&dev);
+        let mut this = Self {
+            dev,
+            path,
+            identity,
+            alias,
+            friendly_name: String::new(),
+            grabbed: false,
+            passthrough: None,
+            passthrough_grabbed: false,
+        };
+        this.friendly_name = match &this.alias {
+            Some(alias) => alias.clone(),
+            None => String::from(this.base_friendly_name()),
+        };
+        this
+    }
+
+    pub fn open(path: &Path, clock: Clock) -> DeviceResult<Self> {
+        let mut dev = Device::new_from_file(std::fs::File::open(path)?)?;
+        dev.set_clock_id(clock.raw_id())?;
+        Ok(Self::new(dev, path.to_path_buf()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Make this device's identity different from one that's already
+    /// taken, e.g. by an unplugged device that used to have the same
+    /// device node.
+    pub fn disambiguate_identity(&mut self, n: usize) {
+        self.identity = format!(
+            "{}:{}#{}",
+            self.path.display(),
+            self.phys().unwrap_or(""),
+            n
+        );
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.dev
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    pub fn phys(&self) -> Option<&str> {
+        self.dev.phys()
     }
 
     pub fn friendly_name(&self) -> DeviceResult<&str> {
-        Ok(friendly_name(self))
+        Ok(&self.friendly_name)
+    }
+
+    /// The friendly name this device would have if nothing else
+    /// shared it.
+    pub fn base_friendly_name(&self) -> &'static str {
+        friendly_name(self)
+    }
+
+    /// A hash of everything that identifies the kind of device: its
+    /// name, vendor and product ids and unique id. The friendly name is
+    /// just one word picked with this, so different devices can share
+    /// a friendly name but rarely a hash.
+    pub fn hash_value(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        self.hash(&mut h);
+        h.finish()
+    }
+
+    pub fn name_claim(&self) -> NameClaim {
+        NameClaim {
+            name: Some(self.friendly_name.clone()),
+            aliased: self.alias.is_some(),
+            base: self.base_friendly_name(),
+            hash: self.hash_value(),
+        }
+    }
+
+    /// Pick a friendly name that no other device has. Aliases are used
+    /// as they are. Otherwise, if a different kind of device hashes to
+    /// the same word, we use two words instead. If another device
+    /// already has the name, we add a number to the end.
+    pub fn choose_friendly_name(&mut self, others: &[NameClaim]) {
+        self.friendly_name = pick_friendly_name(
+            self.alias.as_deref(),
+            self.base_friendly_name(),
+            self.hash_value(),
+            friendly_name_words(&*self, 2),
+            others,
+        );
+    }
+
+    /// Give every device in the list a distinct friendly name. This
+    /// doesn't depend on the order of the list. Identical devices are
+    /// numbered by the port they're plugged into, which stays the same
+    /// between runs, and only then by their device nodes, which don't.
+    pub fn disambiguate_friendly_names(devs: &mut [Self]) {
+        devs.sort_by(|a, b| port_order(a.phys(), &a.path).cmp(&port_order(b.phys(), &b.path)));
+        let mut claims: Vec<NameClaim> = devs
+            .iter()
+            .map(|dev| NameClaim {
+                name: None,
+                ..dev.name_claim()
+            })
+            .collect();
+        for (i, dev) in devs.iter_mut().enumerate() {
+            let others: Vec<NameClaim> = claims
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, claim)| claim.clone())
+                .collect();
+            dev.choose_friendly_name(&others);
+            claims[i].name = Some(dev.friendly_name.clone());
+        }
     }
 
     pub fn get_capabilities(&self) -> DeviceResult<HashSet<EventCode>> {
@@ -60,33 +260,85 @@ impl DeviceContext {
             .collect())
     }
 
+    /// The device's INPUT_PROP_* properties, e.g. INPUT_PROP_POINTER
+    /// for touchpads.
+    pub fn get_properties(&self) -> Vec<InputProp> {
+        evdev_util::all_input_props()
+            .filter(|prop| self.dev.has_property(prop))
+            .collect()
+    }
+
+    /// Every kind of device this is, most specific first.
+    pub fn kinds(&self) -> Vec<DeviceKind> {
+        device_kind::classify(&self.dev)
+    }
+
+    /// The current value of a key, switch, LED or absolute axis, as of
+    /// the events we've read from the device so far. None if the device
+    /// doesn't have the code.
+    pub fn event_value(&self, code: &EventCode) -> Option<i32> {
+        self.dev.event_value(code)
+    }
+
+    /// Every key or button that's currently held down, in code order.
+    pub fn pressed_keys(&self) -> Vec<EventCode> {
+        evdev_util::codes_for(EventType::EV_KEY)
+            .into_iter()
+            .flatten()
+            .filter(|code| self.event_value(code).map_or(false, |value| value != 0))
+            .collect()
+    }
+
     pub fn list_all(clock: Clock) -> DeviceResult<Vec<Self>> {
-        let mut js: Vec<Device> = std::fs::read_dir("/dev/input")?
+        let mut devs: Vec<Self> = std::fs::read_dir("/dev/input")?
             .filter_map(Result::ok)
             .map(|de| de.path())
-            .map(std::fs::File::open)
-            .filter_map(Result::ok)
-            .map(Device::new_from_file)
+            // Don't listen to ourselves
+            .filter(|path| !virtual_input::is_own_devnode(path))
+            .map(|path| Self::open(&path, clock))
             .filter_map(Result::ok)
             .collect();
-        for j in js.iter_mut() {
-            j.set_clock_id(clock.raw_id())?;
-        }
-        Ok(js.into_iter().map(Self::new).collect())
+        Self::disambiguate_friendly_names(&mut devs);
+        Ok(devs)
     }
 
     pub fn list_all_as_userdata<'a>(
         clock: Clock,
         ctx: &rlua::Context<'a>,
     ) -> rlua::Result<Vec<rlua::AnyUserData<'a>>> {
-        let uds = Self::list_all(clock)?
+        Self::list_all(clock)?
             .into_iter()
-            .map(|d| ctx.create_userdata(d))
-            .collect::<Result<Vec<rlua::AnyUserData>, rlua::Error>>()?;
-        for ud in uds.iter() {
-            ud.set_user_value(ctx.create_table()?)?;
+            .map(|d| d.into_userdata(ctx))
+            .collect()
+    }
+
+    pub fn into_userdata<'a>(self, ctx: &rlua::Context<'a>) -> rlua::Result<rlua::AnyUserData<'a>> {
+        let ud = ctx.create_userdata(self)?;
+        ud.set_user_value(ctx.create_table()?)?;
+        Ok(ud)
+    }
+
+    /// Take over a freshly opened device in place of this one,
+    /// e.g. when a device that was unplugged comes back. Grabs and
+    /// pass-through carry over to the new device.
+    pub fn reattach(&mut self, other: DeviceContext) -> DeviceResult<()> {
+        let grabbed = self.grabbed;
+        let passthrough = self.passthrough.take();
+        let passthrough_grabbed = self.passthrough_grabbed;
+        let identity = std::mem::take(&mut self.identity);
+        let friendly_name = std::mem::take(&mut self.friendly_name);
+        *self = other;
+        self.passthrough = passthrough;
+        self.passthrough_grabbed = passthrough_grabbed;
+        self.identity = identity;
+        self.friendly_name = friendly_name;
+        // Not `grab()`, which would turn a grab that pass-through took
+        // into one that outlasts pass-through
+        if grabbed {
+            self.dev.grab(GrabMode::Grab)?;
+            self.grabbed = true;
         }
-        Ok(uds)
+        Ok(())
     }
 
     pub fn next_event(&self) -> DeviceResult<InputEvent> {
@@ -96,9 +348,92 @@ impl DeviceContext {
         Ok(input_event)
     }
 
+    /// The next event that is available without blocking, including
+    /// ones that libevdev has already pulled into its own buffer, or
+    /// None if there isn't one.
+    pub fn pending_event(&self) -> DeviceResult<Option<InputEvent>> {
+        if self.dev.has_event_pending() {
+            Ok(Some(self.next_event()?))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn raw_fd(&self) -> RawFd {
         self.dev.file().as_raw_fd()
     }
+
+    /// Take exclusive access to the device (EVIOCGRAB) so that its
+    /// events are delivered only to us and not to the desktop.
+    pub fn grab(&mut self) -> DeviceResult<()> {
+        // Someone other than pass-through wants the grab now, so it
+        // has to outlast pass-through
+        self.passthrough_grabbed = false;
+        if !self.grabbed {
+            self.dev.grab(GrabMode::Grab)?;
+            self.grabbed = true;
+        }
+        Ok(())
+    }
+
+    pub fn ungrab(&mut self) -> DeviceResult<()> {
+        self.passthrough_grabbed = false;
+        if self.grabbed {
+            self.dev.grab(GrabMode::Ungrab)?;
+            self.grabbed = false;
+        }
+        Ok(())
+    }
+
+    pub fn is_grabbed(&self) -> bool {
+        self.grabbed
+    }
+
+    /// Start or stop forwarding unbound events to a virtual clone of
+    /// this device. Since pass-through only makes sense if the
+    /// original events don't also reach the desktop, enabling it grabs
+    /// the device, and disabling it lets go again unless something
+    /// else has asked for the grab since.
+    pub fn set_passthrough(&mut self, enabled: bool) -> DeviceResult<()> {
+        if enabled && self.passthrough.is_none() {
+            let clone = UInputDevice::create_from_device(&self.dev)?;
+            virtual_input::note_own_device(&clone);
+            self.passthrough = Some(clone);
+            if !self.grabbed {
+                self.grab()?;
+                self.passthrough_grabbed = true;
+            }
+        } else if !enabled {
+            self.passthrough = None;
+            if self.passthrough_grabbed {
+                self.ungrab()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_passthrough(&self) -> bool {
+        self.passthrough.is_some()
+    }
+
+    /// Forward an event to the pass-through clone, if there is one.
+    pub fn forward(&self, input: &InputEvent) -> DeviceResult<()> {
+        if let Some(clone) = &self.passthrough {
+            clone.write_event(input)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DeviceContext {
+    fn drop(&mut self) {
+        // The kernel drops the grab when the fd is closed anyway, so
+        // this only matters if something else is holding the file
+        // open, but it's cheap to be explicit.
+        if self.grabbed {
+            let _ = self.dev.grab(GrabMode::Ungrab);
+        }
+    }
 }
 
 impl UserData for DeviceContext {
@@ -107,6 +442,8 @@ impl UserData for DeviceContext {
             this.friendly_name().map(|s| s.to_lua(ctx))?
         });
 
+        methods.add_method("alias", |_, this, _: ()| Ok(this.alias.clone()));
+
         methods.add_method("name", |_, this, _: ()| {
             Ok(this.dev.name().map(String::from))
         });
@@ -119,6 +456,58 @@ impl UserData for DeviceContext {
 
         methods.add_method("vendor_id", |_, this, _: ()| Ok(this.dev.vendor_id()));
 
+        methods.add_method("phys", |_, this, _: ()| {
+            Ok(this.dev.phys().map(String::from))
+        });
+
+        methods.add_method("bustype", |_, this, _: ()| Ok(this.dev.bustype()));
+
+        methods.add_method("version", |_, this, _: ()| Ok(this.dev.version()));
+
+        methods.add_method("driver_version", |_, this, _: ()| {
+            Ok(this.dev.driver_version())
+        });
+
+        methods.add_method("path", |_, this, _: ()| {
+            Ok(this.path.to_string_lossy().into_owned())
+        });
+
+        methods.add_method("kind", |_, this, _: ()| {
+            Ok(this.kinds().first().map(|kind| kind.as_str()))
+        });
+
+        methods.add_method("kinds", |ctx, this, _: ()| {
+            ctx.create_sequence_from(this.kinds().into_iter().map(|kind| kind.as_str()))
+        });
+
+        methods.add_method("is_kind", |_, this, kind: String| {
+            Ok(this.kinds().contains(&DeviceKind::from_name(&kind)?))
+        });
+
+        methods.add_method("properties", |ctx, this, _: ()| {
+            ctx.create_sequence_from(
+                this.get_properties()
+                    .into_iter()
+                    .map(|prop| prop.to_string()),
+            )
+        });
+
+        methods.add_method("value", |_, this, code: String| {
+            let code = evdev_util::event_code_from_str(code.clone())
+                .map_err(|_| DeviceError::InvalidEventCode(code))?;
+            Ok(this.event_value(&code))
+        });
+
+        methods.add_method("is_pressed", |_, this, code: String| {
+            let code = evdev_util::event_code_from_str(code.clone())
+                .map_err(|_| DeviceError::InvalidEventCode(code))?;
+            Ok(this.event_value(&code).map_or(false, |value| value != 0))
+        });
+
+        methods.add_method("pressed_keys", |ctx, this, _: ()| {
+            ctx.create_sequence_from(this.pressed_keys().into_iter().map(|code| code.to_string()))
+        });
+
         methods.add_method("axis_info", |ctx, this, axis: String| {
             let code = &EventCode::EV_ABS(
                 // <EV_ABS as FromStr>::Err is just (), so not only do
@@ -141,6 +530,20 @@ impl UserData for DeviceContext {
             }
         });
 
+        methods.add_method_mut("grab", |_, this, _: ()| Ok(this.grab()?));
+
+        methods.add_method_mut("ungrab", |_, this, _: ()| Ok(this.ungrab()?));
+
+        methods.add_method("is_grabbed", |_, this, _: ()| Ok(this.is_grabbed()));
+
+        methods.add_method_mut("set_passthrough", |_, this, enabled: bool| {
+            Ok(this.set_passthrough(enabled)?)
+        });
+
+        methods.add_method("has_passthrough", |_, this, _: ()| {
+            Ok(this.has_passthrough())
+        });
+
         methods.add_method("get_caps", |ctx, this, _: ()| {
             ctx.create_sequence_from(
                 this.get_capabilities()?
@@ -150,3 +553,90 @@ impl UserData for DeviceContext {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(name: &str, base: &'static str, hash: u64) -> NameClaim {
+        NameClaim {
+            name: Some(String::from(name)),
+            aliased: false,
+            base,
+            hash,
+        }
+    }
+
+    fn pick(others: &[NameClaim]) -> String {
+        pick_friendly_name(None, "cactus", 1, String::from("cactus-lemon"), others)
+    }
+
+    #[test]
+    fn lone_device_gets_one_word() {
+        assert_eq!(pick(&[]), "cactus");
+        assert_eq!(pick(&[claim("otter", "otter", 2)]), "cactus");
+    }
+
+    #[test]
+    fn hash_collision_gets_two_words() {
+        assert_eq!(pick(&[claim("cactus-pear", "cactus", 2)]), "cactus-lemon");
+    }
+
+    #[test]
+    fn hash_collision_with_aliased_device_keeps_one_word() {
+        let aliased = NameClaim {
+            aliased: true,
+            ..claim("left-pad", "cactus", 2)
+        };
+        assert_eq!(pick(&[aliased]), "cactus");
+    }
+
+    #[test]
+    fn identical_devices_are_numbered() {
+        let first = claim("cactus", "cactus", 1);
+        assert_eq!(pick(&[first.clone()]), "cactus-2");
+        assert_eq!(pick(&[first, claim("cactus-2", "cactus", 1)]), "cactus-3");
+    }
+
+    #[test]
+    fn shared_alias_is_numbered() {
+        let others = [NameClaim {
+            aliased: true,
+            ..claim("pad", "otter", 2)
+        }];
+        assert_eq!(
+            pick_friendly_name(
+                Some("pad"),
+                "cactus",
+                1,
+                String::from("cactus-lemon"),
+                &others
+            ),
+            "pad-2"
+        );
+    }
+
+    #[test]
+    fn numbered_by_port_then_numeric_node() {
+        let mut devs = vec![
+            (Some("usb-2/input0"), PathBuf::from("/dev/input/event3")),
+            (Some("usb-1/input0"), PathBuf::from("/dev/input/event10")),
+            (None, PathBuf::from("/dev/input/event10")),
+            (None, PathBuf::from("/dev/input/event9")),
+        ];
+        devs.sort_by(|a, b| port_order(a.0, &a.1).cmp(&port_order(b.0, &b.1)));
+        let order: Vec<(Option<&str>, &str)> = devs
+            .iter()
+            .map(|(phys, path)| (*phys, path.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (None, "/dev/input/event9"),
+                (None, "/dev/input/event10"),
+                (Some("usb-1/input0"), "/dev/input/event10"),
+                (Some("usb-2/input0"), "/dev/input/event3"),
+            ]
+        );
+    }
+}
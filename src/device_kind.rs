@@ -0,0 +1,262 @@
+use evdev_rs::enums::{EventCode, EventType, InputProp, EV_ABS, EV_KEY, EV_REL};
+use evdev_rs::{Device, DeviceWrapper};
+use std::fmt;
+use std::ops::Range;
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown device kind `{0}`")]
+pub struct UnknownKindError(String);
+
+impl From<UnknownKindError> for rlua::Error {
+    fn from(e: UnknownKindError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+/// What sort of device something is, following the same heuristics as
+/// udev's input_id builtin (ID_INPUT_KEYBOARD, ID_INPUT_JOYSTICK and so
+/// on). A device can be several kinds at once.
+///
+/// The variants are in order of how specific they are, so the first
+/// kind a device has is the most useful description of it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceKind {
+    TabletPad,
+    Tablet,
+    Touchscreen,
+    Touchpad,
+    Joystick,
+    PointingStick,
+    Mouse,
+    Accelerometer,
+    Keyboard,
+    Key,
+    Switch,
+}
+
+impl DeviceKind {
+    pub const ALL: [DeviceKind; 11] = [
+        DeviceKind::TabletPad,
+        DeviceKind::Tablet,
+        DeviceKind::Touchscreen,
+        DeviceKind::Touchpad,
+        DeviceKind::Joystick,
+        DeviceKind::PointingStick,
+        DeviceKind::Mouse,
+        DeviceKind::Accelerometer,
+        DeviceKind::Keyboard,
+        DeviceKind::Key,
+        DeviceKind::Switch,
+    ];
+
+    /// The kind with the given `as_str` name.
+    pub fn from_name(name: &str) -> Result<Self, UnknownKindError> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.as_str() == name)
+            .ok_or_else(|| UnknownKindError(String::from(name)))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceKind::TabletPad => "tablet_pad",
+            DeviceKind::Tablet => "tablet",
+            DeviceKind::Touchscreen => "touchscreen",
+            DeviceKind::Touchpad => "touchpad",
+            DeviceKind::Joystick => "joystick",
+            DeviceKind::PointingStick => "pointing_stick",
+            DeviceKind::Mouse => "mouse",
+            DeviceKind::Accelerometer => "accelerometer",
+            DeviceKind::Keyboard => "keyboard",
+            DeviceKind::Key => "key",
+            DeviceKind::Switch => "switch",
+        }
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Raw code numbers from linux/input-event-codes.h, for the ranges that
+// udev checks
+const KEY_ESC: u32 = 1;
+const BTN_MISC: u32 = 0x100;
+const BTN_MOUSE: u32 = 0x110;
+const BTN_JOYSTICK: u32 = 0x120;
+const BTN_DIGI: u32 = 0x140;
+const KEY_OK: u32 = 0x160;
+const BTN_DPAD_UP: u32 = 0x220;
+const BTN_DPAD_RIGHT: u32 = 0x223;
+const KEY_ALS_TOGGLE: u32 = 0x230;
+const BTN_TRIGGER_HAPPY1: u32 = 0x2c0;
+const BTN_TRIGGER_HAPPY40: u32 = 0x2e7;
+const ABS_RX: u32 = 0x03;
+const ABS_PRESSURE: u32 = 0x18;
+const ABS_MT_SLOT: u32 = 0x2f;
+const BUS_I2C: u16 = 0x18;
+
+fn has_key(dev: &Device, code: u32) -> bool {
+    dev.has(evdev_rs::util::int_to_event_code(
+        EventType::EV_KEY as u32,
+        code,
+    ))
+}
+
+fn has_any_key(dev: &Device, mut codes: Range<u32>) -> bool {
+    codes.any(|code| has_key(dev, code))
+}
+
+fn has_abs(dev: &Device, code: EV_ABS) -> bool {
+    dev.has(EventCode::EV_ABS(code))
+}
+
+fn has_abs_raw(dev: &Device, code: u32) -> bool {
+    dev.has(evdev_rs::util::int_to_event_code(
+        EventType::EV_ABS as u32,
+        code,
+    ))
+}
+
+fn has_rel(dev: &Device, code: EV_REL) -> bool {
+    dev.has(EventCode::EV_REL(code))
+}
+
+/// Port of udev's test_pointers()
+fn pointer_kinds(dev: &Device) -> Vec<DeviceKind> {
+    let has_keys = dev.has(EventType::EV_KEY);
+    let has_abs_coordinates = has_abs(dev, EV_ABS::ABS_X) && has_abs(dev, EV_ABS::ABS_Y);
+    let has_3d_coordinates = has_abs_coordinates && has_abs(dev, EV_ABS::ABS_Z);
+    if dev.has_property(&InputProp::INPUT_PROP_ACCELEROMETER) || (!has_keys && has_3d_coordinates) {
+        return vec![DeviceKind::Accelerometer];
+    }
+
+    let mut is_pointing_stick = dev.has_property(&InputProp::INPUT_PROP_POINTING_STICK);
+    let has_stylus = dev.has(EventCode::EV_KEY(EV_KEY::BTN_STYLUS));
+    let has_pen = dev.has(EventCode::EV_KEY(EV_KEY::BTN_TOOL_PEN));
+    let finger_but_no_pen = dev.has(EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER)) && !has_pen;
+    let has_mouse_button = has_any_key(dev, BTN_MOUSE..BTN_JOYSTICK);
+    let has_rel_coordinates = has_rel(dev, EV_REL::REL_X) && has_rel(dev, EV_REL::REL_Y);
+    // Devices that claim to have every abs axis don't really have
+    // multitouch
+    let has_mt_coordinates = has_abs(dev, EV_ABS::ABS_MT_POSITION_X)
+        && has_abs(dev, EV_ABS::ABS_MT_POSITION_Y)
+        && !(has_abs_raw(dev, ABS_MT_SLOT) && has_abs_raw(dev, ABS_MT_SLOT - 1));
+    let is_direct = dev.has_property(&InputProp::INPUT_PROP_DIRECT);
+    let has_touch = dev.has(EventCode::EV_KEY(EV_KEY::BTN_TOUCH));
+    let has_pad_buttons = has_key(dev, BTN_MISC) && has_key(dev, BTN_MISC + 1) && !has_pen;
+    let has_wheel = has_rel(dev, EV_REL::REL_WHEEL) || has_rel(dev, EV_REL::REL_HWHEEL);
+
+    // A mouse with more than 16 buttons runs into the joystick range,
+    // so don't count joystick buttons if it looks like that's happened
+    let has_joystick_buttons = !has_key(dev, BTN_JOYSTICK - 1)
+        && (has_any_key(dev, BTN_JOYSTICK..BTN_DIGI)
+            || has_any_key(dev, BTN_TRIGGER_HAPPY1..BTN_TRIGGER_HAPPY40 + 1)
+            || has_any_key(dev, BTN_DPAD_UP..BTN_DPAD_RIGHT + 1));
+    let has_joystick_axes = (ABS_RX..ABS_PRESSURE).any(|code| has_abs_raw(dev, code));
+    let has_joystick_axes_or_buttons = has_joystick_buttons || has_joystick_axes;
+
+    let mut is_tablet = false;
+    let mut is_tablet_pad = false;
+    let mut is_touchpad = false;
+    let mut is_touchscreen = false;
+    let mut is_joystick = false;
+    let mut is_mouse = false;
+    if has_abs_coordinates {
+        if has_stylus || has_pen {
+            is_tablet = true;
+        } else if finger_but_no_pen && !is_direct {
+            is_touchpad = true;
+        } else if has_mouse_button {
+            // e.g. VMware's USB mouse, which has absolute axes but no
+            // touch or pressure button
+            is_mouse = true;
+        } else if has_touch || is_direct {
+            is_touchscreen = true;
+        } else if has_joystick_axes_or_buttons {
+            is_joystick = true;
+        }
+    } else if has_joystick_axes_or_buttons {
+        is_joystick = true;
+    }
+
+    if has_mt_coordinates {
+        if has_stylus || has_pen {
+            is_tablet = true;
+        } else if finger_but_no_pen && !is_direct {
+            is_touchpad = true;
+        } else if has_touch || is_direct {
+            is_touchscreen = true;
+        }
+    }
+
+    if is_tablet && has_pad_buttons {
+        is_tablet_pad = true;
+    }
+    if has_pad_buttons && has_wheel && !has_rel_coordinates {
+        is_tablet = true;
+        is_tablet_pad = true;
+    }
+
+    if !is_tablet
+        && !is_touchpad
+        && !is_joystick
+        && has_mouse_button
+        && (has_rel_coordinates || !has_abs_coordinates)
+    {
+        is_mouse = true;
+    }
+    // There's no such thing as an i2c mouse
+    if is_mouse && dev.bustype() == BUS_I2C {
+        is_pointing_stick = true;
+    }
+
+    [
+        (is_tablet_pad, DeviceKind::TabletPad),
+        (is_tablet, DeviceKind::Tablet),
+        (is_touchscreen, DeviceKind::Touchscreen),
+        (is_touchpad, DeviceKind::Touchpad),
+        (is_joystick, DeviceKind::Joystick),
+        (is_pointing_stick, DeviceKind::PointingStick),
+        (is_mouse, DeviceKind::Mouse),
+    ]
+    .iter()
+    .filter(|(is, _)| *is)
+    .map(|(_, kind)| *kind)
+    .collect()
+}
+
+/// Port of udev's test_key()
+fn key_kinds(dev: &Device) -> Vec<DeviceKind> {
+    if !dev.has(EventType::EV_KEY) {
+        return Vec::new();
+    }
+    // Only KEY_*, not BTN_*
+    let has_any_keys = has_any_key(dev, KEY_ESC..BTN_MISC)
+        || has_any_key(dev, KEY_OK..BTN_DPAD_UP)
+        || has_any_key(dev, KEY_ALS_TOGGLE..BTN_TRIGGER_HAPPY1);
+    let mut kinds = Vec::new();
+    // The first 32 codes are escape, the numbers and Q to D. If a device
+    // has all of those, it's a full keyboard.
+    if (KEY_ESC..32).all(|code| has_key(dev, code)) {
+        kinds.push(DeviceKind::Keyboard);
+    }
+    if has_any_keys {
+        kinds.push(DeviceKind::Key);
+    }
+    kinds
+}
+
+/// Every kind the device is, most specific first.
+pub fn classify(dev: &Device) -> Vec<DeviceKind> {
+    let mut kinds = pointer_kinds(dev);
+    kinds.extend(key_kinds(dev));
+    if dev.has(EventType::EV_SW) {
+        kinds.push(DeviceKind::Switch);
+    }
+    kinds.sort();
+    kinds
+}
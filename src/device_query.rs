@@ -0,0 +1,182 @@
+use crate::device::DeviceContext;
+use crate::device_kind::{DeviceKind, UnknownKindError};
+use crate::evdev_util::{self, CodeFromStrError};
+use evdev_rs::enums::EventCode;
+use evdev_rs::DeviceWrapper;
+use regex::Regex;
+use rlua::prelude::{LuaContext, LuaError, LuaResult, LuaValue};
+use rlua::FromLua;
+
+#[derive(thiserror::Error, Debug)]
+pub enum QueryError {
+    #[error("unknown device query field `{0}`")]
+    UnknownField(String),
+    #[error("invalid name_regex")]
+    InvalidRegex(#[from] regex::Error),
+    #[error(transparent)]
+    InvalidEventCode(#[from] CodeFromStrError),
+    #[error(transparent)]
+    UnknownKind(#[from] UnknownKindError),
+}
+
+impl From<QueryError> for rlua::Error {
+    fn from(e: QueryError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+const FIELDS: &[&str] = &[
+    "name",
+    "name_regex",
+    "vendor_id",
+    "product_id",
+    "uniq",
+    "phys",
+    "bustype",
+    "path",
+    "alias",
+    "friendly_name",
+    "caps",
+    "kind",
+];
+
+/// What `find_devices` looks for. A device has to match every field
+/// that's given.
+#[derive(Debug, Default)]
+pub struct DeviceQuery {
+    /// Has to appear somewhere in the device's name
+    name: Option<String>,
+    name_regex: Option<Regex>,
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    uniq: Option<String>,
+    phys: Option<String>,
+    bustype: Option<u16>,
+    path: Option<String>,
+    alias: Option<String>,
+    friendly_name: Option<String>,
+    /// Event codes the device has to support, all of them
+    caps: Vec<EventCode>,
+    /// One of the kinds the device has to be, e.g. "keyboard"
+    kind: Option<DeviceKind>,
+}
+
+impl<'lua> FromLua<'lua> for DeviceQuery {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        let t = match lua_value {
+            LuaValue::Nil => return Ok(Self::default()),
+            LuaValue::Table(t) => t,
+            _ => return Err(LuaError::external("device query must be a table")),
+        };
+        // Catch typos, since a misspelled field would otherwise match
+        // every device
+        for pair in t.clone().pairs::<String, LuaValue>() {
+            let (field, _) = pair?;
+            if !FIELDS.contains(&field.as_str()) {
+                return Err(QueryError::UnknownField(field).into());
+            }
+        }
+        let name_regex = match t.get::<_, Option<String>>("name_regex")? {
+            Some(re) => Some(Regex::new(&re).map_err(QueryError::from)?),
+            None => None,
+        };
+        let caps = t
+            .get::<_, Option<Vec<String>>>("caps")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(evdev_util::event_code_from_str)
+            .collect::<Result<_, _>>()
+            .map_err(QueryError::from)?;
+        // Like a misspelled field, a misspelled kind would otherwise
+        // quietly match nothing
+        let kind = match t.get::<_, Option<String>>("kind")? {
+            Some(name) => Some(DeviceKind::from_name(&name).map_err(QueryError::from)?),
+            None => None,
+        };
+        Ok(Self {
+            name: t.get("name")?,
+            name_regex,
+            vendor_id: t.get("vendor_id")?,
+            product_id: t.get("product_id")?,
+            uniq: t.get("uniq")?,
+            phys: t.get("phys")?,
+            bustype: t.get("bustype")?,
+            path: t.get("path")?,
+            alias: t.get("alias")?,
+            friendly_name: t.get("friendly_name")?,
+            caps,
+            kind,
+        })
+    }
+}
+
+fn matches_opt<T: PartialEq>(wanted: &Option<T>, actual: T) -> bool {
+    wanted.as_ref().map_or(true, |wanted| *wanted == actual)
+}
+
+impl DeviceQuery {
+    pub fn matches(&self, dev_ctx: &DeviceContext) -> bool {
+        let dev = dev_ctx.device();
+        let name = dev.name().unwrap_or("");
+        self.name
+            .as_ref()
+            .map_or(true, |n| name.contains(n.as_str()))
+            && self
+                .name_regex
+                .as_ref()
+                .map_or(true, |re| re.is_match(name))
+            && matches_opt(&self.vendor_id, dev.vendor_id())
+            && matches_opt(&self.product_id, dev.product_id())
+            && matches_opt(&self.uniq.as_deref(), dev.uniq().unwrap_or(""))
+            && matches_opt(&self.phys.as_deref(), dev.phys().unwrap_or(""))
+            && matches_opt(&self.bustype, dev.bustype())
+            && matches_opt(&self.path.as_deref(), dev_ctx.path().to_str().unwrap_or(""))
+            && matches_opt(&self.alias.as_deref(), dev_ctx.alias().unwrap_or(""))
+            && self.friendly_name.as_ref().map_or(true, |f| {
+                dev_ctx
+                    .friendly_name()
+                    .map_or(false, |actual| actual == f.as_str())
+            })
+            && self.caps.iter().all(|code| dev.has(*code))
+            && self
+                .kind
+                .map_or(true, |kind| dev_ctx.kinds().contains(&kind))
+    }
+}
+
+/// Sort devices by things that don't change between runs, so that a
+/// script that takes the first match gets the same device every time.
+/// The device node only breaks ties between identical devices.
+pub fn sort_key(dev_ctx: &DeviceContext) -> impl Ord {
+    let dev = dev_ctx.device();
+    (
+        String::from(dev.name().unwrap_or("")),
+        dev.vendor_id(),
+        dev.product_id(),
+        String::from(dev.uniq().unwrap_or("")),
+        String::from(dev.phys().unwrap_or("")),
+        dev_ctx.path().to_path_buf(),
+    )
+}
+
+/// Every device in `DEVICES` that matches the query, sorted.
+pub fn find_devices<'lua>(
+    ctx: &rlua::Context<'lua>,
+    query: &DeviceQuery,
+) -> rlua::Result<Vec<rlua::AnyUserData<'lua>>> {
+    let devices = ctx.globals().get::<_, rlua::Table>("DEVICES")?;
+    let mut found = Vec::new();
+    for dev_ud in devices.sequence_values::<rlua::AnyUserData>() {
+        let dev_ud = dev_ud?;
+        let key = {
+            let dev_ctx = dev_ud.borrow::<DeviceContext>()?;
+            if !query.matches(&dev_ctx) {
+                continue;
+            }
+            sort_key(&dev_ctx)
+        };
+        found.push((key, dev_ud));
+    }
+    found.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(found.into_iter().map(|(_, dev_ud)| dev_ud).collect())
+}
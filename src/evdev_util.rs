@@ -1,4 +1,4 @@
-use evdev_rs::enums::{EventCode, EventType};
+use evdev_rs::enums::{EventCode, EventType, InputProp};
 use evdev_rs::util::EventCodeIterator;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
@@ -31,6 +31,13 @@ pub fn all_event_codes() -> EventCodeIterator {
     EventCode::iter(&MIN_CODES[&MIN_TYPE])
 }
 
+/// INPUT_PROP_MAX from linux/input-event-codes.h
+const INPUT_PROP_MAX: u32 = 0x1f;
+
+pub fn all_input_props() -> impl Iterator<Item = InputProp> {
+    (0..=INPUT_PROP_MAX).filter_map(evdev_rs::util::int_to_input_prop)
+}
+
 pub struct CodesInTypeIter {
     internal: EventCodeIterator,
     ev_type: EventType,
@@ -84,6 +91,11 @@ pub fn event_code_from_str(s: String) -> Result<EventCode, CodeFromStrError> {
     Ok(result)
 }
 
+pub fn event_type_of(code: &EventCode) -> Option<EventType> {
+    let (type_raw, _) = evdev_rs::util::event_code_to_int(code);
+    evdev_rs::util::int_to_event_type(type_raw)
+}
+
 pub fn type_of_event_code(code: &str) -> &str {
     if code == "BTN" {
         "KEY"
@@ -91,3 +103,56 @@ pub fn type_of_event_code(code: &str) -> &str {
         code
     }
 }
+
+/// Match `s` against a shell-style pattern where `*` matches any run of
+/// characters and `?` matches exactly one.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    fn go(p: &[u8], s: &[u8]) -> bool {
+        match p.split_first() {
+            None => s.is_empty(),
+            Some((b'*', rest)) => (0..=s.len()).any(|i| go(rest, &s[i..])),
+            Some((b'?', rest)) => !s.is_empty() && go(rest, &s[1..]),
+            Some((c, rest)) => s.first() == Some(c) && go(rest, &s[1..]),
+        }
+    }
+    go(pattern.as_bytes(), s.as_bytes())
+}
+
+pub fn is_glob(s: &str) -> bool {
+    s.contains(|c| c == '*' || c == '?')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob_match("KEY_A", "KEY_A"));
+        assert!(!glob_match("KEY_A", "KEY_AB"));
+        assert!(!glob_match("KEY_AB", "KEY_A"));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("BTN_*", "BTN_SOUTH"));
+        assert!(glob_match("BTN_*", "BTN_"));
+        assert!(glob_match("*_X", "ABS_X"));
+        assert!(glob_match("A*S*X", "ABS_HAT0X"));
+        assert!(!glob_match("BTN_*", "KEY_A"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("ABS_HAT?X", "ABS_HAT0X"));
+        assert!(!glob_match("ABS_HAT?X", "ABS_HATX"));
+        assert!(!glob_match("ABS_HAT?X", "ABS_HAT10X"));
+    }
+
+    #[test]
+    fn is_glob_needs_a_wildcard() {
+        assert!(is_glob("BTN_*"));
+        assert!(is_glob("ABS_HAT?X"));
+        assert!(!is_glob("KEY_A"));
+    }
+}
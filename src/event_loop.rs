@@ -0,0 +1,222 @@
+use crate::bindings;
+use crate::coroutines;
+use crate::device::{DeviceContext, DeviceError};
+use crate::hotplug::{self, Hotplug, HotplugEvent};
+use crate::time_util;
+use crate::timers;
+use crate::virtual_input;
+use nix::sys::epoll::{
+    epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+pub const POLL_FD_NAME: &str = "poll_fd";
+
+const MAX_EVENTS: usize = 64;
+
+/// What a file descriptor in the epoll set belongs to. This gets
+/// packed into the epoll event's data field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Hotplug,
+    Device(RawFd),
+    Timer(RawFd),
+}
+
+impl From<Token> for u64 {
+    fn from(token: Token) -> u64 {
+        match token {
+            Token::Hotplug => 0,
+            Token::Device(fd) => (1 << 32) | fd as u64,
+            Token::Timer(fd) => (2 << 32) | fd as u64,
+        }
+    }
+}
+
+impl From<u64> for Token {
+    fn from(data: u64) -> Token {
+        let fd = (data & 0xffff_ffff) as RawFd;
+        match data >> 32 {
+            0 => Token::Hotplug,
+            1 => Token::Device(fd),
+            2 => Token::Timer(fd),
+            other => panic!("unknown epoll token kind `{}`", other),
+        }
+    }
+}
+
+/// Create the epoll set up front so that things like timers can be
+/// added to it while the script is still starting up.
+pub fn set_up_event_loop(ctx: &rlua::Context) -> rlua::Result<()> {
+    let pollfd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).map_err(rlua::Error::external)?;
+    ctx.set_named_registry_value(POLL_FD_NAME, pollfd)
+}
+
+pub fn watch(ctx: &rlua::Context, fd: RawFd, token: Token) -> rlua::Result<()> {
+    let pollfd = ctx.named_registry_value::<str, RawFd>(POLL_FD_NAME)?;
+    let mut ev = EpollEvent::new(EpollFlags::EPOLLIN, token.into());
+    epoll_ctl(pollfd, EpollOp::EpollCtlAdd, fd, &mut ev).map_err(rlua::Error::external)
+}
+
+pub fn unwatch(ctx: &rlua::Context, fd: RawFd) -> rlua::Result<()> {
+    let pollfd = ctx.named_registry_value::<str, RawFd>(POLL_FD_NAME)?;
+    // Linux ignores the event for EpollCtlDel, but very old kernels
+    // want it to be non-null
+    let mut ev = EpollEvent::empty();
+    epoll_ctl(pollfd, EpollOp::EpollCtlDel, fd, &mut ev).map_err(rlua::Error::external)
+}
+
+pub struct EventLoop<'lua> {
+    ctx: rlua::Context<'lua>,
+    pollfd: RawFd,
+    /// Created by `hotplug::set_up_hotplug` and kept in the registry
+    hotplug: rlua::AnyUserData<'lua>,
+    devices: HashMap<RawFd, rlua::AnyUserData<'lua>>,
+}
+
+impl<'lua> EventLoop<'lua> {
+    pub fn new(ctx: rlua::Context<'lua>) -> rlua::Result<Self> {
+        let pollfd = ctx.named_registry_value::<str, RawFd>(POLL_FD_NAME)?;
+        let hotplug = ctx.named_registry_value::<str, rlua::AnyUserData>(hotplug::HOTPLUG_NAME)?;
+        let hotplug_fd = hotplug.borrow::<Hotplug>()?.as_raw_fd();
+        let mut this = Self {
+            ctx,
+            pollfd,
+            hotplug,
+            devices: HashMap::new(),
+        };
+        watch(&ctx, hotplug_fd, Token::Hotplug)?;
+        let devices = ctx.globals().get::<_, rlua::Table>("DEVICES")?;
+        for dev_ud in devices.sequence_values::<rlua::AnyUserData>() {
+            this.add_device(dev_ud?)?;
+        }
+        Ok(this)
+    }
+
+    pub fn run(&mut self) -> rlua::Result<()> {
+        if !self.has_work()? {
+            eprintln!("No bindings, hotplug hooks, timers or pass-through devices, nothing to do");
+            return Ok(());
+        }
+
+        let mut events = vec![EpollEvent::empty(); MAX_EVENTS];
+        loop {
+            let n = match epoll_wait(self.pollfd, &mut events, -1) {
+                Err(nix::errno::Errno::EINTR) => continue,
+                other => other.map_err(rlua::Error::external)?,
+            };
+            for event in events[..n].iter() {
+                match Token::from(event.data()) {
+                    Token::Hotplug => self.handle_hotplug()?,
+                    Token::Device(fd) => self.handle_device(fd, event.events())?,
+                    Token::Timer(fd) => timers::fire(&self.ctx, fd)?,
+                }
+            }
+        }
+    }
+
+    fn has_work(&self) -> rlua::Result<bool> {
+        if bindings::any_device_has_bindings(&self.ctx)?
+            || hotplug::has_hooks(&self.ctx)?
+            || timers::any_active(&self.ctx)?
+        {
+            return Ok(true);
+        }
+        for dev_ud in self.devices.values() {
+            if dev_ud.borrow::<DeviceContext>()?.has_passthrough() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn add_device(&mut self, dev_ud: rlua::AnyUserData<'lua>) -> rlua::Result<()> {
+        let fd = dev_ud.borrow::<DeviceContext>()?.raw_fd();
+        watch(&self.ctx, fd, Token::Device(fd))?;
+        self.devices.insert(fd, dev_ud);
+        Ok(())
+    }
+
+    fn fd_for_path(&self, path: &Path) -> rlua::Result<Option<RawFd>> {
+        for (fd, dev_ud) in self.devices.iter() {
+            if dev_ud.borrow::<DeviceContext>()?.path() == path {
+                return Ok(Some(*fd));
+            }
+        }
+        Ok(None)
+    }
+
+    fn handle_hotplug(&mut self) -> rlua::Result<()> {
+        let events = self
+            .hotplug
+            .borrow::<Hotplug>()?
+            .read_events()
+            .map_err(rlua::Error::external)?;
+        for event in events {
+            match event {
+                HotplugEvent::Added(path) => self.device_added(&path)?,
+                HotplugEvent::Removed(path) => {
+                    if let Some(fd) = self.fd_for_path(&path)? {
+                        self.device_removed(fd)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn device_added(&mut self, path: &Path) -> rlua::Result<()> {
+        if virtual_input::is_own_devnode(path) || self.fd_for_path(path)?.is_some() {
+            return Ok(());
+        }
+        let dev = match DeviceContext::open(path, time_util::CLOCK) {
+            Ok(dev) => dev,
+            // Most likely udev hasn't given us permission to read it
+            // yet, in which case we'll try again when it does
+            Err(_) => return Ok(()),
+        };
+        let dev_ud = hotplug::attach(&self.ctx, dev)?;
+        self.add_device(dev_ud.clone())?;
+        coroutines::run_hooks(&self.ctx, hotplug::DEVICE_ADDED_HOOKS_NAME, dev_ud)
+    }
+
+    fn device_removed(&mut self, fd: RawFd) -> rlua::Result<()> {
+        if let Some(dev_ud) = self.devices.remove(&fd) {
+            unwatch(&self.ctx, fd)?;
+            hotplug::detach(&self.ctx, &dev_ud)?;
+            coroutines::run_hooks(&self.ctx, hotplug::DEVICE_REMOVED_HOOKS_NAME, dev_ud)?;
+        }
+        Ok(())
+    }
+
+    fn handle_device(&mut self, fd: RawFd, flags: EpollFlags) -> rlua::Result<()> {
+        let dev_ud = match self.devices.get(&fd) {
+            Some(dev_ud) => dev_ud.clone(),
+            None => return Ok(()),
+        };
+        if flags.intersects(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR) {
+            return self.device_removed(fd);
+        }
+        // Read one event at a time and dispatch it before reading the
+        // next, so that callbacks see the device's state as of the
+        // event they're handling rather than the end of the batch.
+        // Don't hold on to the device while we run callbacks, since
+        // they may want to borrow it mutably.
+        loop {
+            let result = dev_ud.borrow::<DeviceContext>()?.pending_event();
+            let input = match result {
+                Ok(Some(input)) => input,
+                Ok(None) => break,
+                Err(DeviceError::Io(e)) if e.raw_os_error() == Some(nix::libc::ENODEV) => {
+                    return self.device_removed(fd);
+                }
+                Err(e) => return Err(e.into()),
+            };
+            coroutines::wake_waiters(&self.ctx, &dev_ud, &input)?;
+            bindings::dispatch_or_forward(&self.ctx, &dev_ud, &input)?;
+        }
+        Ok(())
+    }
+}
@@ -7,6 +7,23 @@ use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 
 pub fn friendly_name<T>(t: T) -> &'static str
+where
+    T: Hash,
+{
+    friendly_words(t).next().unwrap()
+}
+
+/// Like [`friendly_name`], but with more words for when one isn't
+/// enough to tell things apart. The first word is always the same as
+/// the one [`friendly_name`] picks.
+pub fn friendly_name_words<T>(t: T, n: usize) -> String
+where
+    T: Hash,
+{
+    friendly_words(t).take(n).collect::<Vec<_>>().join("-")
+}
+
+fn friendly_words<T>(t: T) -> impl Iterator<Item = &'static str>
 where
     T: Hash,
 {
@@ -23,5 +40,5 @@ where
     t.hash(&mut h);
     let hash = h.finish();
     let mut rng: StdRng = SeedableRng::seed_from_u64(hash);
-    WORDS.choose(&mut rng).unwrap()
+    std::iter::repeat_with(move || *WORDS.choose(&mut rng).unwrap())
 }
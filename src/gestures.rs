@@ -0,0 +1,440 @@
+use crate::bindings::{self, BindOptions, BindTarget};
+use crate::coroutines;
+use crate::evdev_util;
+use crate::timers;
+use evdev_rs::enums::EventType;
+use rlua::prelude::{LuaContext, LuaError, LuaResult, LuaValue};
+use rlua::{FromLua, UserData, UserDataMethods};
+
+#[derive(thiserror::Error, Debug)]
+pub enum GestureError {
+    #[error("gestures can only be bound to a single key or button, not `{0}`")]
+    NotAKey(String),
+}
+
+impl From<GestureError> for rlua::Error {
+    fn from(e: GestureError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+const DEFAULT_THRESHOLD: f64 = 0.2;
+const DEFAULT_INTERVAL: f64 = 0.3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GestureKind {
+    /// Pressed and released within the threshold
+    Tap,
+    /// Held for longer than the threshold
+    Hold,
+    /// Tapped twice, with the second press coming within the interval
+    /// of the first release
+    DoubleTap,
+    /// A tap or a hold, whichever it turns out to be. Pressing another
+    /// key while this one is down makes it a hold straight away, so that
+    /// e.g. space-as-shift works when typing quickly.
+    TapHold,
+}
+
+impl GestureKind {
+    fn has_hold(&self) -> bool {
+        matches!(self, GestureKind::Hold | GestureKind::TapHold)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GestureOptions {
+    /// Longest press, in seconds, that still counts as a tap
+    pub threshold: f64,
+    /// Longest gap, in seconds, between the taps of a double tap
+    pub interval: f64,
+    pub priority: i64,
+}
+
+impl Default for GestureOptions {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            interval: DEFAULT_INTERVAL,
+            priority: 0,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for GestureOptions {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        let defaults = Self::default();
+        match lua_value {
+            LuaValue::Nil => Ok(defaults),
+            LuaValue::Table(t) => Ok(Self {
+                threshold: t
+                    .get::<_, Option<f64>>("threshold")?
+                    .unwrap_or(defaults.threshold),
+                interval: t
+                    .get::<_, Option<f64>>("interval")?
+                    .unwrap_or(defaults.interval),
+                priority: t
+                    .get::<_, Option<i64>>("priority")?
+                    .unwrap_or(defaults.priority),
+            }),
+            _ => Err(LuaError::external("gesture options must be a table")),
+        }
+    }
+}
+
+/// What a gesture has recognized and needs to tell the script about
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Action {
+    Tap,
+    DoubleTap,
+    /// 1 when a hold starts, 0 when it ends
+    Hold(i32),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum State {
+    Idle,
+    /// Pressed, and we don't know what it is yet
+    Pressed {
+        since: f64,
+    },
+    /// Held long enough to be a hold, and still held
+    Holding,
+    /// Tapped once, waiting to see whether there's a second tap
+    Tapped {
+        released: f64,
+    },
+    /// Pressed again soon enough after a tap
+    PressedAgain {
+        since: f64,
+    },
+}
+
+/// Recognizes one kind of gesture on one key, with its callbacks, the
+/// bindings that feed events in and the hold timer, if one is running,
+/// alongside it (see [`timers::start_from`]).
+#[derive(Debug)]
+pub struct Gesture {
+    kind: GestureKind,
+    code: String,
+    options: GestureOptions,
+    state: State,
+    /// Bumped on every press
+    generation: u64,
+}
+
+impl Gesture {
+    fn new(kind: GestureKind, code: String, options: GestureOptions) -> Self {
+        Self {
+            kind,
+            code,
+            options,
+            state: State::Idle,
+            generation: 0,
+        }
+    }
+
+    fn press(&mut self, time: f64) {
+        self.state = match self.state {
+            State::Tapped { released }
+                if self.kind == GestureKind::DoubleTap
+                    && time - released <= self.options.interval =>
+            {
+                State::PressedAgain { since: time }
+            }
+            State::Holding => State::Holding,
+            _ => {
+                self.generation += 1;
+                State::Pressed { since: time }
+            }
+        };
+    }
+
+    fn release(&mut self, time: f64) -> Vec<Action> {
+        let state = std::mem::replace(&mut self.state, State::Idle);
+        match state {
+            State::Pressed { since } if time - since <= self.options.threshold => match self.kind {
+                GestureKind::Tap | GestureKind::TapHold => vec![Action::Tap],
+                GestureKind::DoubleTap => {
+                    self.state = State::Tapped { released: time };
+                    vec![]
+                }
+                GestureKind::Hold => vec![],
+            },
+            // Held too long for a tap, but we found out on release
+            // rather than from the timer
+            State::Pressed { .. } if self.kind.has_hold() => vec![Action::Hold(1), Action::Hold(0)],
+            State::Holding => vec![Action::Hold(0)],
+            State::PressedAgain { since } if time - since <= self.options.threshold => {
+                vec![Action::DoubleTap]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The hold timer for the given press went off
+    fn timeout(&mut self, generation: u64) -> Vec<Action> {
+        match self.state {
+            State::Pressed { .. } if generation == self.generation && self.kind.has_hold() => {
+                self.state = State::Holding;
+                vec![Action::Hold(1)]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Another key on the same device was pressed
+    fn interrupt(&mut self) -> Vec<Action> {
+        match self.state {
+            State::Pressed { .. } if self.kind == GestureKind::TapHold => {
+                self.state = State::Holding;
+                vec![Action::Hold(1)]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+impl UserData for Gesture {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function("unbind", |ctx, gesture_ud: rlua::AnyUserData| {
+            let user_value = gesture_ud.get_user_value::<rlua::Table>()?;
+            let was_bound = bindings::unbind_all(&ctx, user_value.get("bindings")?)?;
+            if let Some(timer_ud) = user_value.get::<_, Option<rlua::AnyUserData>>("timer")? {
+                timers::cancel(&ctx, &timer_ud)?;
+                user_value.set("timer", rlua::Value::Nil)?;
+            }
+            gesture_ud.borrow_mut::<Gesture>()?.state = State::Idle;
+            Ok(was_bound)
+        });
+    }
+}
+
+fn run_actions<'lua>(
+    ctx: &rlua::Context<'lua>,
+    gesture_ud: &rlua::AnyUserData<'lua>,
+    actions: Vec<Action>,
+) -> rlua::Result<()> {
+    let callbacks = gesture_ud.get_user_value::<rlua::Table>()?;
+    for action in actions {
+        let (name, value) = match action {
+            Action::Tap => ("tap", None),
+            Action::DoubleTap => ("double_tap", None),
+            Action::Hold(value) => ("hold", Some(value)),
+        };
+        if let Some(callback) = callbacks.get::<_, Option<rlua::Function>>(name)? {
+            coroutines::spawn(ctx, callback, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Feed a press or release of the gesture's key in.
+fn handle_event<'lua>(
+    ctx: rlua::Context<'lua>,
+    (gesture_ud, event): (rlua::AnyUserData<'lua>, rlua::Table<'lua>),
+) -> rlua::Result<()> {
+    let value: i32 = event.get("value")?;
+    let time: f64 = event.get("time")?;
+    let actions = match value {
+        1 => {
+            let (pressed_since, generation, threshold) = {
+                let mut gesture = gesture_ud.borrow_mut::<Gesture>()?;
+                gesture.press(time);
+                match gesture.state {
+                    State::Pressed { since } if gesture.kind.has_hold() => {
+                        (Some(since), gesture.generation, gesture.options.threshold)
+                    }
+                    _ => (None, gesture.generation, gesture.options.threshold),
+                }
+            };
+            if let Some(since) = pressed_since {
+                let on_timeout = ctx
+                    .create_function(handle_timeout)?
+                    .bind((gesture_ud.clone(), generation))?;
+                let timer_ud =
+                    timers::start_from(&ctx, since, threshold, rlua::Value::Function(on_timeout))?;
+                // Kept so that unbinding can stop it
+                gesture_ud
+                    .get_user_value::<rlua::Table>()?
+                    .set("timer", timer_ud)?;
+            }
+            Vec::new()
+        }
+        0 => gesture_ud.borrow_mut::<Gesture>()?.release(time),
+        // Ignore autorepeat
+        _ => Vec::new(),
+    };
+    run_actions(&ctx, &gesture_ud, actions)
+}
+
+fn handle_timeout<'lua>(
+    ctx: rlua::Context<'lua>,
+    (gesture_ud, generation): (rlua::AnyUserData<'lua>, u64),
+) -> rlua::Result<()> {
+    let actions = gesture_ud.borrow_mut::<Gesture>()?.timeout(generation);
+    run_actions(&ctx, &gesture_ud, actions)
+}
+
+/// Watch for other keys being pressed while the gesture's key is down.
+fn handle_other_key<'lua>(
+    ctx: rlua::Context<'lua>,
+    (gesture_ud, event): (rlua::AnyUserData<'lua>, rlua::Table<'lua>),
+) -> rlua::Result<()> {
+    if event.get::<_, i32>("value")? != 1 {
+        return Ok(());
+    }
+    let actions = {
+        let mut gesture = gesture_ud.borrow_mut::<Gesture>()?;
+        if event.get::<_, String>("code")? == gesture.code {
+            return Ok(());
+        }
+        gesture.interrupt()
+    };
+    run_actions(&ctx, &gesture_ud, actions)
+}
+
+/// Start recognizing a gesture on a key. `callbacks` has whichever of
+/// `tap`, `hold` and `double_tap` the gesture needs.
+pub fn bind<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    code: String,
+    kind: GestureKind,
+    callbacks: rlua::Table<'lua>,
+    options: GestureOptions,
+) -> rlua::Result<rlua::AnyUserData<'lua>> {
+    let event_code = evdev_util::event_code_from_str(code.clone())
+        .map_err(|_| GestureError::NotAKey(code.clone()))?;
+    if evdev_util::event_type_of(&event_code) != Some(EventType::EV_KEY) {
+        return Err(GestureError::NotAKey(code).into());
+    }
+    let code = event_code.to_string();
+
+    let gesture_ud = ctx.create_userdata(Gesture::new(kind, code.clone(), options))?;
+    gesture_ud.set_user_value(callbacks.clone())?;
+
+    let mut handles = Vec::new();
+    let on_event = ctx
+        .create_function(handle_event)?
+        .bind(gesture_ud.clone())?;
+    handles.push(bindings::set_in_bindings_table(
+        ctx,
+        dev_ud,
+        &BindTarget::Code(code),
+        on_event,
+        BindOptions {
+            full: true,
+            priority: options.priority,
+            observe: false,
+            layer: None,
+        },
+    )?);
+    if kind == GestureKind::TapHold {
+        let on_other_key = ctx
+            .create_function(handle_other_key)?
+            .bind(gesture_ud.clone())?;
+        // So that the hold starts before the other key is handled
+        handles.push(bindings::set_in_bindings_table(
+            ctx,
+            dev_ud,
+            &BindTarget::Type(EventType::EV_KEY),
+            on_other_key,
+            BindOptions {
+                full: true,
+                priority: bindings::FIRST_PRIORITY,
+                observe: true,
+                layer: None,
+            },
+        )?);
+    }
+    callbacks.set("bindings", handles)?;
+    Ok(gesture_ud)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gesture(kind: GestureKind) -> Gesture {
+        Gesture::new(kind, String::from("KEY_SPACE"), GestureOptions::default())
+    }
+
+    #[test]
+    fn tap_within_threshold() {
+        let mut gesture = gesture(GestureKind::Tap);
+        gesture.press(0.0);
+        assert_eq!(gesture.release(0.1), vec![Action::Tap]);
+    }
+
+    #[test]
+    fn tap_held_too_long_is_nothing() {
+        let mut gesture = gesture(GestureKind::Tap);
+        gesture.press(0.0);
+        assert!(gesture.timeout(gesture.generation).is_empty());
+        assert!(gesture.release(0.5).is_empty());
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut gesture = gesture(GestureKind::DoubleTap);
+        gesture.press(0.0);
+        assert!(gesture.release(0.1).is_empty());
+        gesture.press(0.3);
+        assert_eq!(gesture.release(0.4), vec![Action::DoubleTap]);
+    }
+
+    #[test]
+    fn double_tap_too_slow_starts_over() {
+        let mut gesture = gesture(GestureKind::DoubleTap);
+        gesture.press(0.0);
+        gesture.release(0.1);
+        gesture.press(0.5);
+        assert!(gesture.release(0.6).is_empty());
+        gesture.press(0.7);
+        assert_eq!(gesture.release(0.8), vec![Action::DoubleTap]);
+    }
+
+    #[test]
+    fn hold_from_timer() {
+        let mut gesture = gesture(GestureKind::Hold);
+        gesture.press(0.0);
+        assert_eq!(gesture.timeout(gesture.generation), vec![Action::Hold(1)]);
+        assert_eq!(gesture.release(1.0), vec![Action::Hold(0)]);
+    }
+
+    #[test]
+    fn hold_noticed_on_release() {
+        let mut gesture = gesture(GestureKind::Hold);
+        gesture.press(0.0);
+        assert_eq!(gesture.release(0.5), vec![Action::Hold(1), Action::Hold(0)]);
+    }
+
+    #[test]
+    fn stale_hold_timer_does_nothing() {
+        let mut gesture = gesture(GestureKind::TapHold);
+        gesture.press(0.0);
+        let stale = gesture.generation;
+        assert_eq!(gesture.release(0.1), vec![Action::Tap]);
+        gesture.press(0.3);
+        assert!(gesture.timeout(stale).is_empty());
+        assert_eq!(gesture.release(0.4), vec![Action::Tap]);
+    }
+
+    #[test]
+    fn tap_hold_interrupted_by_another_key() {
+        let mut gesture = gesture(GestureKind::TapHold);
+        gesture.press(0.0);
+        assert_eq!(gesture.interrupt(), vec![Action::Hold(1)]);
+        assert!(gesture.interrupt().is_empty());
+        assert_eq!(gesture.release(0.1), vec![Action::Hold(0)]);
+    }
+
+    #[test]
+    fn plain_hold_ignores_other_keys() {
+        let mut gesture = gesture(GestureKind::Hold);
+        gesture.press(0.0);
+        assert!(gesture.interrupt().is_empty());
+        assert!(gesture.release(0.1).is_empty());
+    }
+}
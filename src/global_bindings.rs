@@ -1,9 +1,19 @@
+use crate::chords::{self, ChordOptions};
+use crate::coroutines;
 use crate::device::DeviceContext;
+use crate::device_query::{self, DeviceQuery};
 use crate::evdev_util;
+use crate::gestures::{self, GestureKind, GestureOptions};
 use rlua::prelude::{LuaContext, LuaResult};
+use rlua::FromLua;
 
-use crate::bindings::set_in_bindings_table;
+use crate::bindings::{set_in_bindings_table, BindOptions, BindTarget};
+use crate::hotplug;
+use crate::layers::{self, Layer};
+use crate::sequences::{self, SequenceOptions};
 use crate::time_util;
+use crate::timers;
+use crate::virtual_input::{VirtualInput, VirtualInputConfig};
 
 pub fn make_sleep(ctx: &LuaContext) -> LuaResult<()> {
     ctx.globals().set(
@@ -15,20 +25,91 @@ pub fn make_sleep(ctx: &LuaContext) -> LuaResult<()> {
     )
 }
 
+pub fn make_now(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "now",
+        ctx.create_function(|_, ()| time_util::CLOCK.now().map_err(rlua::Error::external))?,
+    )
+}
+
+pub fn make_timers(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "after",
+        ctx.create_function(|ctx, (secs, callback): (f64, rlua::Function)| {
+            timers::start(&ctx, secs, None, rlua::Value::Function(callback))
+        })?,
+    )?;
+    ctx.globals().set(
+        "every",
+        ctx.create_function(|ctx, (secs, callback): (f64, rlua::Function)| {
+            timers::start(&ctx, secs, Some(secs), rlua::Value::Function(callback))
+        })?,
+    )
+}
+
 pub fn make_bind(ctx: &LuaContext) -> LuaResult<()> {
     ctx.globals().set(
         "bind",
         ctx.create_function(
-            move |ctx, (dev_handle, event, callback): (rlua::AnyUserData, String, rlua::Function)| {
-                let event_code =
-                    evdev_util::event_code_from_str(event).map_err(rlua::Error::external)?;
-                set_in_bindings_table(&ctx, &dev_handle, &event_code, callback)?;
-                Ok(())
+            move |ctx,
+                  (dev_handle, event, callback, options): (
+                rlua::AnyUserData,
+                String,
+                rlua::Function,
+                BindOptions,
+            )| {
+                let target = BindTarget::parse(event)?;
+                set_in_bindings_table(&ctx, &dev_handle, &target, callback, options)
             },
         )?,
     )
 }
 
+pub fn make_layers(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "layer",
+        ctx.create_function(|_, name: String| Ok(Layer::new(name)?))?,
+    )?;
+    ctx.globals().set(
+        "active_layers",
+        ctx.create_function(|ctx, ()| layers::active_layers(&ctx))?,
+    )
+}
+
+pub fn make_hotplug_hooks(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "on_device_added",
+        ctx.create_function(|ctx, hook: rlua::Function| {
+            coroutines::add_hook(&ctx, hotplug::DEVICE_ADDED_HOOKS_NAME, hook)
+        })?,
+    )?;
+    ctx.globals().set(
+        "on_device_removed",
+        ctx.create_function(|ctx, hook: rlua::Function| {
+            coroutines::add_hook(&ctx, hotplug::DEVICE_REMOVED_HOOKS_NAME, hook)
+        })?,
+    )
+}
+
+pub fn make_create_device(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "create_device",
+        ctx.create_function(|_, config: VirtualInputConfig| {
+            Ok(VirtualInput::new(time_util::CLOCK, &config)?)
+        })?,
+    )?;
+    ctx.globals().set(
+        "create_gamepad",
+        ctx.create_function(|_, options: Option<rlua::Table>| {
+            let mut config = VirtualInputConfig::gamepad();
+            if let Some(options) = options {
+                config = config.with_overrides(&options)?;
+            }
+            Ok(VirtualInput::new(time_util::CLOCK, &config)?)
+        })?,
+    )
+}
+
 pub fn make_all_event_codes(ctx: &LuaContext) -> LuaResult<()> {
     ctx.globals().set(
         "CODES",
@@ -43,11 +124,104 @@ pub fn make_device_userdatas(ctx: &LuaContext) -> LuaResult<()> {
     )
 }
 
+pub fn make_gestures(ctx: &LuaContext) -> LuaResult<()> {
+    for (name, kind, callback_name) in &[
+        ("bind_tap", GestureKind::Tap, "tap"),
+        ("bind_hold", GestureKind::Hold, "hold"),
+        ("bind_double_tap", GestureKind::DoubleTap, "double_tap"),
+    ] {
+        let (kind, callback_name) = (*kind, *callback_name);
+        ctx.globals().set(
+            *name,
+            ctx.create_function(
+                move |ctx,
+                      (dev_handle, code, callback, options): (
+                    rlua::AnyUserData,
+                    String,
+                    rlua::Function,
+                    GestureOptions,
+                )| {
+                    let callbacks = ctx.create_table()?;
+                    callbacks.set(callback_name, callback)?;
+                    gestures::bind(&ctx, &dev_handle, code, kind, callbacks, options)
+                },
+            )?,
+        )?;
+    }
+    ctx.globals().set(
+        "bind_tap_hold",
+        ctx.create_function(|ctx, t: rlua::Table| {
+            let callbacks = ctx.create_table()?;
+            callbacks.set("tap", t.get::<_, Option<rlua::Function>>("tap")?)?;
+            callbacks.set("hold", t.get::<_, Option<rlua::Function>>("hold")?)?;
+            let options = GestureOptions::from_lua(rlua::Value::Table(t.clone()), ctx)?;
+            gestures::bind(
+                &ctx,
+                &t.get::<_, rlua::AnyUserData>("device")?,
+                t.get("code")?,
+                GestureKind::TapHold,
+                callbacks,
+                options,
+            )
+        })?,
+    )
+}
+
+pub fn make_bind_chord(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "bind_chord",
+        ctx.create_function(
+            |ctx,
+             (members, callback, options): (
+                Vec<rlua::Table>,
+                rlua::Function,
+                ChordOptions,
+            )| chords::bind(&ctx, members, callback, options),
+        )?,
+    )
+}
+
+pub fn make_bind_sequence(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "bind_sequence",
+        ctx.create_function(
+            |ctx,
+             (dev_handle, codes, callback, options): (
+                rlua::AnyUserData,
+                Vec<String>,
+                rlua::Function,
+                SequenceOptions,
+            )| sequences::bind(&ctx, &dev_handle, codes, callback, options),
+        )?,
+    )?;
+    ctx.globals().set(
+        "on_sequence_progress",
+        ctx.create_function(|ctx, hook: rlua::Function| {
+            coroutines::add_hook(&ctx, sequences::SEQUENCE_PROGRESS_HOOKS_NAME, hook)
+        })?,
+    )?;
+    ctx.globals().set(
+        "on_sequence_cancel",
+        ctx.create_function(|ctx, hook: rlua::Function| {
+            coroutines::add_hook(&ctx, sequences::SEQUENCE_CANCEL_HOOKS_NAME, hook)
+        })?,
+    )
+}
+
+pub fn make_find_devices(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.globals().set(
+        "find_devices",
+        ctx.create_function(|ctx, query: DeviceQuery| device_query::find_devices(&ctx, &query))?,
+    )
+}
+
 pub fn make_included_luas(ctx: &LuaContext) -> LuaResult<()> {
+    ctx.load(include_bytes!("lua/sleep.lua")).exec()?;
     ctx.load(include_bytes!("lua/find_device_by_friendly_name.lua"))
         .exec()?;
     ctx.load(include_bytes!("lua/find_device_by_ids.lua"))
         .exec()?;
+    ctx.load(include_bytes!("lua/find_device.lua")).exec()?;
     ctx.load(include_bytes!("lua/sendkey.lua")).exec()?;
     ctx.load(include_bytes!("lua/sendkeys.lua")).exec()?;
     Ok(())
@@ -0,0 +1,198 @@
+use crate::bindings;
+use crate::device::{DeviceContext, NameClaim};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+pub const INPUT_DIR: &str = "/dev/input";
+pub const DEVICE_ADDED_HOOKS_NAME: &str = "device_added_hooks";
+pub const DEVICE_REMOVED_HOOKS_NAME: &str = "device_removed_hooks";
+pub const DETACHED_DEVICES_NAME: &str = "detached_devices";
+pub const HOTPLUG_NAME: &str = "hotplug";
+
+#[derive(Debug)]
+pub enum HotplugEvent {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// Watches `/dev/input` for event nodes coming and going.
+#[derive(Debug)]
+pub struct Hotplug {
+    inotify: Inotify,
+}
+
+impl Hotplug {
+    pub fn new() -> nix::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        // The kernel creates device nodes before udev gets around to
+        // fixing up their permissions, so we also watch for attribute
+        // changes and try again then.
+        inotify.add_watch(
+            INPUT_DIR,
+            AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_DELETE,
+        )?;
+        Ok(Self { inotify })
+    }
+
+    pub fn read_events(&self) -> nix::Result<Vec<HotplugEvent>> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(nix::errno::Errno::EAGAIN) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(events
+            .into_iter()
+            .filter_map(|event| {
+                let name = event.name?;
+                if !name.to_string_lossy().starts_with("event") {
+                    return None;
+                }
+                let path = Path::new(INPUT_DIR).join(name);
+                if event.mask.contains(AddWatchFlags::IN_DELETE) {
+                    Some(HotplugEvent::Removed(path))
+                } else {
+                    Some(HotplugEvent::Added(path))
+                }
+            })
+            .collect())
+    }
+}
+
+impl rlua::UserData for Hotplug {}
+
+impl AsRawFd for Hotplug {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+/// This has to happen before the devices that are already plugged in
+/// are listed, so that one that turns up while we're listing them is
+/// waiting for the event loop rather than missed.
+pub fn set_up_hotplug(ctx: &rlua::Context) -> rlua::Result<()> {
+    ctx.set_named_registry_value(HOTPLUG_NAME, Hotplug::new().map_err(rlua::Error::external)?)?;
+    ctx.set_named_registry_value(DEVICE_ADDED_HOOKS_NAME, ctx.create_table()?)?;
+    ctx.set_named_registry_value(DEVICE_REMOVED_HOOKS_NAME, ctx.create_table()?)?;
+    ctx.set_named_registry_value(DETACHED_DEVICES_NAME, ctx.create_table()?)?;
+    Ok(())
+}
+
+pub fn has_hooks(ctx: &rlua::Context) -> rlua::Result<bool> {
+    for hooks_name in [DEVICE_ADDED_HOOKS_NAME, DEVICE_REMOVED_HOOKS_NAME].iter() {
+        if ctx
+            .named_registry_value::<str, rlua::Table>(hooks_name)?
+            .len()?
+            > 0
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Turn a newly opened device into a userdata and add it to
+/// `DEVICES`. If a matching device was unplugged earlier we reuse its
+/// userdata, so that bindings and handles the script is holding on to
+/// keep working.
+pub fn attach<'lua>(
+    ctx: &rlua::Context<'lua>,
+    mut dev: DeviceContext,
+) -> rlua::Result<rlua::AnyUserData<'lua>> {
+    let detached = ctx.named_registry_value::<str, rlua::Table>(DETACHED_DEVICES_NAME)?;
+    let dev_ud = match find_detached(&detached, &dev)? {
+        Some((dev_name, dev_ud)) => {
+            detached.set(dev_name, rlua::Value::Nil)?;
+            dev_ud.borrow_mut::<DeviceContext>()?.reattach(dev)?;
+            dev_ud
+        }
+        None => {
+            // Some other device that's currently unplugged may have had
+            // this device node, and it's still got its bindings
+            let mut n = 1;
+            while bindings::is_known_device(ctx, &bindings::device_key(&dev)?)? {
+                n += 1;
+                dev.disambiguate_identity(n);
+            }
+            dev.choose_friendly_name(&name_claims(ctx, &detached)?);
+            bindings::add_device(ctx, &dev)?;
+            dev.into_userdata(ctx)?
+        }
+    };
+    let devices = ctx.globals().get::<_, rlua::Table>("DEVICES")?;
+    devices.set(devices.len()? + 1, dev_ud.clone())?;
+    Ok(dev_ud)
+}
+
+/// The friendly names of every device we know about, plugged in or
+/// not. A new device's name mustn't clash with any of them; we never
+/// rename a device the script may already have looked up.
+fn name_claims(ctx: &rlua::Context, detached: &rlua::Table) -> rlua::Result<Vec<NameClaim>> {
+    let mut claims = Vec::new();
+    let devices = ctx.globals().get::<_, rlua::Table>("DEVICES")?;
+    for dev_ud in devices.sequence_values::<rlua::AnyUserData>() {
+        claims.push(dev_ud?.borrow::<DeviceContext>()?.name_claim());
+    }
+    for pair in detached.clone().pairs::<String, rlua::AnyUserData>() {
+        let (_, dev_ud) = pair?;
+        claims.push(dev_ud.borrow::<DeviceContext>()?.name_claim());
+    }
+    Ok(claims)
+}
+
+/// Find the unplugged device that a newly opened one is most likely to
+/// be: the same kind of device, going by its name, ids and unique id,
+/// preferably plugged into the same port.
+fn find_detached<'lua>(
+    detached: &rlua::Table<'lua>,
+    dev: &DeviceContext,
+) -> rlua::Result<Option<(String, rlua::AnyUserData<'lua>)>> {
+    let hash = dev.hash_value();
+    let mut found = None;
+    for pair in detached.clone().pairs::<String, rlua::AnyUserData>() {
+        let (dev_name, dev_ud) = pair?;
+        let (same_kind, same_port) = {
+            let other = dev_ud.borrow::<DeviceContext>()?;
+            (other.hash_value() == hash, other.phys() == dev.phys())
+        };
+        if same_kind && same_port {
+            return Ok(Some((dev_name, dev_ud)));
+        } else if same_kind && found.is_none() {
+            found = Some((dev_name, dev_ud));
+        }
+    }
+    Ok(found)
+}
+
+/// Remove an unplugged device from `DEVICES` and remember it so that
+/// [`attach`] can bring it back.
+pub fn detach<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+) -> rlua::Result<()> {
+    let (dev_name, path) = {
+        let dev = dev_ud.borrow::<DeviceContext>()?;
+        (bindings::device_key(&dev)?, dev.path().to_path_buf())
+    };
+
+    // Edit DEVICES in place rather than replacing it in case the
+    // script is holding on to the table
+    let devices = ctx.globals().get::<_, rlua::Table>("DEVICES")?;
+    let mut remaining = Vec::new();
+    for other in devices.clone().sequence_values::<rlua::AnyUserData>() {
+        let other = other?;
+        if other.borrow::<DeviceContext>()?.path() != path {
+            remaining.push(other);
+        }
+    }
+    for i in 1..=devices.len()? {
+        devices.set(i, rlua::Value::Nil)?;
+    }
+    for (i, other) in remaining.into_iter().enumerate() {
+        devices.set(i + 1, other)?;
+    }
+
+    let detached = ctx.named_registry_value::<str, rlua::Table>(DETACHED_DEVICES_NAME)?;
+    detached.set(dev_name, dev_ud.clone())?;
+    Ok(())
+}
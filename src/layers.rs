@@ -0,0 +1,313 @@
+use crate::bindings::{self, BindOptions, BindTarget};
+use rlua::{UserData, UserDataMethods};
+use std::collections::HashMap;
+
+pub const LAYERS_NAME: &str = "layers";
+
+#[derive(thiserror::Error, Debug)]
+pub enum LayerError {
+    #[error("layer names can't be empty")]
+    EmptyName,
+}
+
+impl From<LayerError> for rlua::Error {
+    fn from(e: LayerError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+#[derive(Debug)]
+struct ActiveLayer {
+    name: String,
+    /// Turn off again after the next key press
+    one_shot: bool,
+}
+
+/// Which named layers are active, and in what order. Bindings that
+/// aren't in a named layer are in the base layer, which is always
+/// active and always at the bottom.
+#[derive(Debug, Default)]
+pub struct LayerStack {
+    /// Lowest first
+    active: Vec<ActiveLayer>,
+    /// The layer that each held key's press went to, by device and
+    /// code, so that its release goes there too even if the layers
+    /// have changed in the meantime. `None` is the base layer.
+    held: HashMap<(String, String), Option<String>>,
+}
+
+impl LayerStack {
+    /// How high up the stack the layer is, or `None` if it isn't active.
+    /// The base layer is 0.
+    pub fn rank(&self, layer: Option<&str>) -> Option<usize> {
+        match layer {
+            None => Some(0),
+            Some(name) => self
+                .active
+                .iter()
+                .position(|l| l.name == name)
+                .map(|i| i + 1),
+        }
+    }
+
+    fn on(&mut self, name: &str, one_shot: bool) {
+        self.off(name);
+        self.active.push(ActiveLayer {
+            name: String::from(name),
+            one_shot,
+        });
+    }
+
+    /// Returns whether the layer was active.
+    fn off(&mut self, name: &str) -> bool {
+        let before = self.active.len();
+        self.active.retain(|l| l.name != name);
+        self.active.len() != before
+    }
+
+    fn one_shots(&self) -> Vec<String> {
+        self.active
+            .iter()
+            .filter(|l| l.one_shot)
+            .map(|l| l.name.clone())
+            .collect()
+    }
+
+    /// Active layers, top first
+    fn names(&self) -> Vec<String> {
+        self.active.iter().rev().map(|l| l.name.clone()).collect()
+    }
+}
+
+impl UserData for LayerStack {}
+
+pub fn set_up_layers(ctx: &rlua::Context) -> rlua::Result<()> {
+    ctx.set_named_registry_value(LAYERS_NAME, LayerStack::default())
+}
+
+fn with_stack<R>(ctx: &rlua::Context, f: impl FnOnce(&mut LayerStack) -> R) -> rlua::Result<R> {
+    let stack_ud = ctx.named_registry_value::<str, rlua::AnyUserData>(LAYERS_NAME)?;
+    let mut stack = stack_ud.borrow_mut::<LayerStack>()?;
+    Ok(f(&mut stack))
+}
+
+pub fn rank(ctx: &rlua::Context, layer: Option<&str>) -> rlua::Result<Option<usize>> {
+    with_stack(ctx, |stack| stack.rank(layer))
+}
+
+pub fn active_layers(ctx: &rlua::Context) -> rlua::Result<Vec<String>> {
+    with_stack(ctx, |stack| stack.names())
+}
+
+/// The layers that a key press should turn off once it's been
+/// dispatched. Taken before dispatching, so that a press that turns a
+/// one-shot layer on doesn't also turn it straight back off.
+pub fn pending_one_shots(ctx: &rlua::Context) -> rlua::Result<Vec<String>> {
+    with_stack(ctx, |stack| stack.one_shots())
+}
+
+pub fn end_one_shots(ctx: &rlua::Context, names: &[String]) -> rlua::Result<()> {
+    with_stack(ctx, |stack| {
+        for name in names {
+            // It may have been turned on again as something other than
+            // a one-shot since
+            if stack.active.iter().any(|l| &l.name == name && l.one_shot) {
+                stack.off(name);
+            }
+        }
+    })
+}
+
+/// The layer that the press of a key still being held went to, if we
+/// saw it.
+pub fn held_layer(
+    ctx: &rlua::Context,
+    device: &str,
+    code: &str,
+) -> rlua::Result<Option<Option<String>>> {
+    with_stack(ctx, |stack| {
+        stack
+            .held
+            .get(&(String::from(device), String::from(code)))
+            .cloned()
+    })
+}
+
+pub fn set_held_layer(
+    ctx: &rlua::Context,
+    device: &str,
+    code: &str,
+    layer: Option<Option<String>>,
+) -> rlua::Result<()> {
+    with_stack(ctx, |stack| {
+        let key = (String::from(device), String::from(code));
+        match layer {
+            Some(layer) => stack.held.insert(key, layer),
+            None => stack.held.remove(&key),
+        };
+    })
+}
+
+/// A named layer, handed to Lua by `layer(name)`. It doesn't need to
+/// exist anywhere else until it's turned on or has bindings in it.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    name: String,
+}
+
+impl Layer {
+    pub fn new(name: String) -> Result<Self, LayerError> {
+        if name.is_empty() {
+            return Err(LayerError::EmptyName);
+        }
+        Ok(Self { name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl UserData for Layer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("name", |_, this, ()| Ok(this.name.clone()));
+        methods.add_method("on", |ctx, this, ()| {
+            with_stack(&ctx, |stack| stack.on(&this.name, false))
+        });
+        methods.add_method("off", |ctx, this, ()| {
+            with_stack(&ctx, |stack| stack.off(&this.name))
+        });
+        methods.add_method("toggle", |ctx, this, ()| {
+            with_stack(&ctx, |stack| {
+                let was_active = stack.off(&this.name);
+                if !was_active {
+                    stack.on(&this.name, false);
+                }
+                !was_active
+            })
+        });
+        methods.add_method("one_shot", |ctx, this, ()| {
+            with_stack(&ctx, |stack| stack.on(&this.name, true))
+        });
+        methods.add_method("is_active", |ctx, this, ()| {
+            Ok(rank(&ctx, Some(&this.name))?.is_some())
+        });
+        methods.add_method(
+            "momentary",
+            |ctx, this, (dev_handle, event): (rlua::AnyUserData, String)| {
+                let name = this.name.clone();
+                let callback = ctx.create_function(move |ctx, value: i32| {
+                    with_stack(&ctx, |stack| match value {
+                        1 => stack.on(&name, false),
+                        0 => {
+                            stack.off(&name);
+                        }
+                        _ => (),
+                    })
+                })?;
+                let target = BindTarget::parse(event)?;
+                bindings::set_in_bindings_table(
+                    &ctx,
+                    &dev_handle,
+                    &target,
+                    callback,
+                    BindOptions::default(),
+                )
+            },
+        );
+        methods.add_method(
+            "bind",
+            |ctx,
+             this,
+             (dev_handle, event, callback, options): (
+                rlua::AnyUserData,
+                String,
+                rlua::Function,
+                BindOptions,
+            )| {
+                let target = BindTarget::parse(event)?;
+                let options = BindOptions {
+                    layer: Some(this.name.clone()),
+                    ..options
+                };
+                bindings::set_in_bindings_table(&ctx, &dev_handle, &target, callback, options)
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    #[test]
+    fn layers_stack_in_the_order_they_were_turned_on() {
+        let mut stack = LayerStack::default();
+        stack.on("nav", false);
+        stack.on("num", false);
+        assert_eq!(stack.rank(None), Some(0));
+        assert_eq!(stack.rank(Some("nav")), Some(1));
+        assert_eq!(stack.rank(Some("num")), Some(2));
+        assert_eq!(stack.rank(Some("sym")), None);
+        assert_eq!(stack.names(), ["num", "nav"]);
+    }
+
+    #[test]
+    fn turning_a_layer_on_again_moves_it_to_the_top() {
+        let mut stack = LayerStack::default();
+        stack.on("nav", false);
+        stack.on("num", false);
+        stack.on("nav", false);
+        assert_eq!(stack.names(), ["nav", "num"]);
+    }
+
+    #[test]
+    fn off_leaves_the_rest_in_order() {
+        let mut stack = LayerStack::default();
+        stack.on("nav", false);
+        stack.on("num", false);
+        stack.on("sym", false);
+        assert!(stack.off("num"));
+        assert!(!stack.off("num"));
+        assert_eq!(stack.names(), ["sym", "nav"]);
+        assert_eq!(stack.rank(Some("sym")), Some(2));
+    }
+
+    #[test]
+    fn one_shot_only_ends_if_still_one_shot() {
+        Lua::new().context(|ctx| {
+            set_up_layers(&ctx).unwrap();
+            with_stack(&ctx, |stack| {
+                stack.on("nav", true);
+                stack.on("num", true);
+            })
+            .unwrap();
+            let pending = pending_one_shots(&ctx).unwrap();
+            assert_eq!(pending, ["nav", "num"]);
+            // Turned on for good while the press was being dispatched
+            with_stack(&ctx, |stack| stack.on("num", false)).unwrap();
+            end_one_shots(&ctx, &pending).unwrap();
+            assert_eq!(active_layers(&ctx).unwrap(), ["num"]);
+        });
+    }
+
+    #[test]
+    fn held_layer_outlasts_the_layer() {
+        Lua::new().context(|ctx| {
+            set_up_layers(&ctx).unwrap();
+            with_stack(&ctx, |stack| stack.on("nav", false)).unwrap();
+            set_held_layer(&ctx, "pad", "BTN_SOUTH", Some(Some(String::from("nav")))).unwrap();
+            set_held_layer(&ctx, "pad", "BTN_EAST", Some(None)).unwrap();
+            with_stack(&ctx, |stack| stack.off("nav")).unwrap();
+            assert_eq!(
+                held_layer(&ctx, "pad", "BTN_SOUTH").unwrap(),
+                Some(Some(String::from("nav")))
+            );
+            assert_eq!(held_layer(&ctx, "pad", "BTN_EAST").unwrap(), Some(None));
+            assert_eq!(held_layer(&ctx, "other", "BTN_SOUTH").unwrap(), None);
+            set_held_layer(&ctx, "pad", "BTN_SOUTH", None).unwrap();
+            assert_eq!(held_layer(&ctx, "pad", "BTN_SOUTH").unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,23 @@
+function find_device(t)
+   -- Friendly names get special treatment for names that had to be
+   -- disambiguated
+   local fields = 0
+   for _ in pairs(t) do
+      fields = fields + 1
+   end
+   if t.friendly_name and fields == 1 then
+      return find_device_by_friendly_name(t)
+   end
+
+   local found = find_devices(t)
+   if #found > 1 then
+      local names = {}
+      for _, dev in ipairs(found) do
+         table.insert(names, dev:friendly_name())
+      end
+      io.stderr:write(string.format(
+         "warning: more than one device matches, using %s: %s\n",
+         names[1], table.concat(names, ", ")))
+   end
+   return found[1]
+end
@@ -1,8 +1,36 @@
 function find_device_by_friendly_name(t)
    local friendly_name = assert(t.friendly_name)
+   -- Devices whose names had to be disambiguated start with the name
+   -- they would otherwise have had, e.g. volleyball-cactus or
+   -- volleyball-2
+   local prefix = friendly_name .. "-"
+   local exact = nil
+   local candidates = {}
    for _, dev in pairs(DEVICES) do
-      if dev:friendly_name() == friendly_name then
-         return dev
+      local name = dev:friendly_name()
+      if name == friendly_name then
+         exact = dev
+      elseif name:sub(1, #prefix) == prefix then
+         table.insert(candidates, dev)
       end
    end
+
+   local names = {}
+   for _, dev in ipairs(candidates) do
+      table.insert(names, dev:friendly_name())
+   end
+   if exact then
+      if #candidates > 0 then
+         io.stderr:write(string.format(
+            "warning: friendly name `%s` is ambiguous, it could also mean %s\n",
+            friendly_name, table.concat(names, ", ")))
+      end
+      return exact
+   elseif #candidates > 1 then
+      error(string.format(
+         "friendly name `%s` is ambiguous, use one of %s",
+         friendly_name, table.concat(names, ", ")), 2)
+   else
+      return candidates[1]
+   end
 end
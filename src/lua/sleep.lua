@@ -0,0 +1,16 @@
+local blocking_sleep = sleep
+
+function sleep(seconds)
+   if coroutine.isyieldable() then
+      coroutine.yield("evdotool.sleep", seconds)
+   else
+      blocking_sleep(seconds)
+   end
+end
+
+function wait_for_event(device, code)
+   if not coroutine.isyieldable() then
+      error "wait_for_event can only be used inside a callback"
+   end
+   return coroutine.yield("evdotool.wait_for_event", device, code)
+end
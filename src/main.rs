@@ -1,17 +1,19 @@
 use anyhow::{Context, Result};
-use nix::sys::epoll::{
-    epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
-};
 use rlua::Lua;
-use std::cell::Ref;
 use std::path::PathBuf;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+mod aliases;
+
 mod friendly_name;
 
 mod bindings;
 
+mod chords;
+
+mod coroutines;
+
 mod evdev_util;
 
 mod global_bindings;
@@ -20,10 +22,27 @@ use global_bindings::*;
 mod device;
 use device::DeviceContext;
 
+mod device_kind;
+
+mod device_query;
+
+mod event_loop;
+use event_loop::EventLoop;
+
+mod gestures;
+
+mod hotplug;
+
 mod interpolator;
 
+mod layers;
+
+mod sequences;
+
 mod time_util;
 
+mod timers;
+
 mod virtual_input;
 
 #[derive(Debug, StructOpt)]
@@ -32,6 +51,13 @@ struct EvdotoolOpt {
     /// Run the given script
     #[structopt()]
     script: PathBuf,
+    /// Grab each device the first time a binding is added to it
+    #[structopt(long)]
+    grab: bool,
+    /// Read device aliases from the given file instead of
+    /// ~/.config/evdotool/devices.toml
+    #[structopt(long)]
+    aliases: Option<PathBuf>,
     /// Further args for the script
     #[structopt()]
     script_args: Vec<String>,
@@ -45,13 +71,37 @@ fn main() -> Result<()> {
     let script = std::fs::read_to_string(&opt.script)
         .with_context(|| format!("while reading script {}", opt.script.to_string_lossy()))?;
 
-    let input = virtual_input::VirtualInput::new(time_util::CLOCK)?;
+    // The default aliases file is optional, but one we were given
+    // explicitly isn't
+    let aliases_path = opt
+        .aliases
+        .clone()
+        .or_else(|| aliases::default_path().filter(|path| path.exists()));
+    if let Some(path) = aliases_path {
+        aliases::load(&path)
+            .with_context(|| format!("while loading aliases {}", path.to_string_lossy()))?;
+    }
+
+    let input = virtual_input::VirtualInput::new(
+        time_util::CLOCK,
+        &virtual_input::VirtualInputConfig::default(),
+    )?;
 
     lua.context(|lua_ctx| -> rlua::Result<()> {
         make_sleep(&lua_ctx)?;
+        make_now(&lua_ctx)?;
+        make_timers(&lua_ctx)?;
         make_bind(&lua_ctx)?;
+        make_gestures(&lua_ctx)?;
+        make_bind_chord(&lua_ctx)?;
+        make_bind_sequence(&lua_ctx)?;
+        make_layers(&lua_ctx)?;
+        make_hotplug_hooks(&lua_ctx)?;
+        make_create_device(&lua_ctx)?;
         make_all_event_codes(&lua_ctx)?;
+        hotplug::set_up_hotplug(&lua_ctx)?;
         make_device_userdatas(&lua_ctx)?;
+        make_find_devices(&lua_ctx)?;
         make_included_luas(&lua_ctx)?;
 
         lua_ctx.globals().set("INPUT", input)?;
@@ -60,7 +110,12 @@ fn main() -> Result<()> {
     .with_context(|| "while setting globals")?;
 
     lua.context(|lua_ctx| -> rlua::Result<()> {
-        bindings::set_up_bindings(&lua_ctx)?;
+        bindings::set_up_bindings(&lua_ctx, opt.grab)?;
+        sequences::set_up_sequences(&lua_ctx)?;
+        layers::set_up_layers(&lua_ctx)?;
+        event_loop::set_up_event_loop(&lua_ctx)?;
+        timers::set_up_timers(&lua_ctx)?;
+        coroutines::set_up_coroutines(&lua_ctx)?;
         Ok(())
     })
     .with_context(|| "while setting up bindings")?;
@@ -72,58 +127,8 @@ fn main() -> Result<()> {
     })
     .with_context(|| "while running script")?;
 
-    lua.context(|lua_ctx| -> rlua::Result<()> {
-        let pollfd = epoll_create1(EpollCreateFlags::empty())
-            .with_context(|| "in epoll_create1")
-            .map_err(rlua::Error::external)?;
-        let mut events = Vec::new();
-        let device_userdatas = lua_ctx
-            .globals()
-            .get::<_, rlua::Table>("DEVICES")?
-            .sequence_values::<rlua::AnyUserData>()
-            .collect::<rlua::Result<Vec<rlua::AnyUserData>>>()?;
-        // this has to be defined after device_userdatas because it
-        // borrows an rlua::Function from it and drops happen in
-        // reverse order
-        let mut bound_devices: Vec<Ref<DeviceContext>> = Vec::new();
-        for device_user_data in device_userdatas.iter() {
-            let bound_device = device_user_data.borrow::<DeviceContext>().unwrap();
-            if bindings::device_has_bindings(&lua_ctx, &bound_device)
-                .with_context(|| "device_has_bindings")
-                .map_err(rlua::Error::external)?
-            {
-                let mut ev = EpollEvent::new(EpollFlags::EPOLLIN, bound_devices.len() as u64);
-
-                epoll_ctl(pollfd, EpollOp::EpollCtlAdd, bound_device.raw_fd(), &mut ev)
-                    .with_context(|| "in epoll_ctl")
-                    .map_err(rlua::Error::external)?;
-                bound_devices.push(bound_device);
-                events.push(ev);
-            }
-        }
-
-        if bound_devices.is_empty() {
-            panic!("No bound devices");
-        }
-
-        loop {
-            let _ = epoll_wait(pollfd, &mut events, 1000)
-                .with_context(|| "in epoll_wait")
-                .map_err(rlua::Error::external)?;
-            for event in events.iter() {
-                if event.events().contains(EpollFlags::EPOLLIN) {
-                    let bound_device = &bound_devices[event.data() as usize];
-                    let input = bound_device.next_event()?;
-                    if let Some(callback) =
-                        bindings::get_in_bindings_map(&lua_ctx, bound_device, &input.event_code)?
-                    {
-                        callback.call::<_, ()>(input.value)?;
-                    }
-                }
-            }
-        }
-    })
-    .with_context(|| "while running bindings")?;
+    lua.context(|lua_ctx| -> rlua::Result<()> { EventLoop::new(lua_ctx)?.run() })
+        .with_context(|| "while running bindings")?;
 
     println!("Done!");
 
@@ -0,0 +1,566 @@
+use crate::bindings::{self, BindOptions, BindTarget};
+use crate::coroutines;
+use crate::device::DeviceContext;
+use crate::evdev_util::{self, CodeFromStrError};
+use crate::timers;
+use evdev_rs::enums::EventType;
+use rlua::prelude::{LuaContext, LuaError, LuaResult, LuaValue};
+use rlua::{FromLua, UserData, UserDataMethods};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub const SEQUENCES_NAME: &str = "sequences";
+pub const SEQUENCE_PROGRESS_HOOKS_NAME: &str = "sequence_progress_hooks";
+pub const SEQUENCE_CANCEL_HOOKS_NAME: &str = "sequence_cancel_hooks";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SequenceError {
+    #[error("sequences can only be made of keys and buttons, not `{0}`")]
+    NotAKey(String),
+    #[error("a sequence needs at least one key")]
+    Empty,
+    #[error(transparent)]
+    InvalidEventCode(#[from] CodeFromStrError),
+}
+
+impl From<SequenceError> for rlua::Error {
+    fn from(e: SequenceError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+const DEFAULT_TIMEOUT: f64 = 1.0;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SequenceOptions {
+    /// Longest time, in seconds, to wait for the next key
+    pub timeout: f64,
+}
+
+impl Default for SequenceOptions {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for SequenceOptions {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        let defaults = Self::default();
+        match lua_value {
+            LuaValue::Nil => Ok(defaults),
+            LuaValue::Table(t) => Ok(Self {
+                timeout: t
+                    .get::<_, Option<f64>>("timeout")?
+                    .unwrap_or(defaults.timeout),
+            }),
+            _ => Err(LuaError::external("sequence options must be a table")),
+        }
+    }
+}
+
+/// One step in the trie of bound sequences.
+#[derive(Debug, Default)]
+struct Node {
+    children: BTreeMap<String, Node>,
+    /// The sequence that ends here, if any
+    action: Option<i64>,
+    /// The timeout of every sequence that passes through here, by id.
+    /// We wait for the longest of them.
+    timeouts: HashMap<i64, f64>,
+}
+
+impl Node {
+    fn timeout(&self) -> f64 {
+        self.timeouts.values().cloned().fold(0.0, f64::max)
+    }
+
+    fn insert(&mut self, codes: &[String], id: i64, timeout: f64) {
+        self.timeouts.insert(id, timeout);
+        match codes.split_first() {
+            Some((code, rest)) => self
+                .children
+                .entry(code.clone())
+                .or_default()
+                .insert(rest, id, timeout),
+            None => self.action = Some(id),
+        }
+    }
+
+    /// Returns whether the node is now empty and can be pruned.
+    fn remove(&mut self, codes: &[String], id: i64) -> bool {
+        self.timeouts.remove(&id);
+        match codes.split_first() {
+            Some((code, rest)) => {
+                let prune = match self.children.get_mut(code) {
+                    Some(child) => child.remove(rest, id),
+                    None => false,
+                };
+                if prune {
+                    self.children.remove(code);
+                }
+            }
+            None => {
+                if self.action == Some(id) {
+                    self.action = None;
+                }
+            }
+        }
+        self.children.is_empty() && self.action.is_none()
+    }
+
+    fn get(&self, codes: &[String]) -> Option<&Node> {
+        match codes.split_first() {
+            Some((code, rest)) => self.children.get(code)?.get(rest),
+            None => Some(self),
+        }
+    }
+}
+
+/// What the engine needs to tell the script about, in order
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// The given keys are all that's been typed of some sequence so far
+    Progress {
+        typed: Vec<String>,
+        next: Vec<String>,
+        /// When the last key was pressed, and how long to wait after
+        /// that for the next one
+        since: f64,
+        timeout: f64,
+        generation: u64,
+    },
+    /// The given keys didn't lead anywhere
+    Cancel(Vec<String>),
+    Fire(i64),
+}
+
+/// Matches key presses on one device against every sequence bound on
+/// it. The callbacks for the sequences are kept alongside it by id (see
+/// [`timers::start_from`]).
+#[derive(Debug, Default)]
+pub struct SequenceEngine {
+    root: Node,
+    /// What's been typed so far of the sequence in progress
+    typed: Vec<String>,
+    /// Keys whose presses went to a sequence, so that their releases
+    /// and autorepeats do too
+    swallowed: HashSet<String>,
+    /// Bumped on every key that a sequence takes
+    generation: u64,
+}
+
+impl SequenceEngine {
+    fn current(&self) -> &Node {
+        // Sequences are only ever removed from the trie while nothing's
+        // being typed, so this is always there
+        self.root.get(&self.typed).unwrap_or(&self.root)
+    }
+
+    /// Try to take the next key of a sequence from the current node.
+    fn advance(&mut self, code: &str, time: f64, steps: &mut Vec<Step>) -> bool {
+        let child = match self.current().children.get(code) {
+            Some(child) => child,
+            None => return false,
+        };
+        let (is_leaf, action, next, timeout) = (
+            child.children.is_empty(),
+            child.action,
+            child.children.keys().cloned().collect(),
+            child.timeout(),
+        );
+        self.typed.push(code.to_string());
+        self.swallowed.insert(code.to_string());
+        self.generation += 1;
+        match action {
+            // Nothing longer could match, so don't bother waiting
+            Some(id) if is_leaf => {
+                self.typed.clear();
+                steps.push(Step::Fire(id));
+            }
+            _ => steps.push(Step::Progress {
+                typed: self.typed.clone(),
+                next,
+                since: time,
+                timeout,
+                generation: self.generation,
+            }),
+        }
+        true
+    }
+
+    fn press(&mut self, code: &str, time: f64) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if self.advance(code, time, &mut steps) || self.typed.is_empty() {
+            return steps;
+        }
+        // A wrong key ends the sequence, but could still start another
+        // one. If what's been typed is a whole sequence, it's the one
+        // that was meant, just as if we'd timed out waiting.
+        let action = self.current().action;
+        let typed = std::mem::take(&mut self.typed);
+        steps.push(match action {
+            Some(id) => Step::Fire(id),
+            None => Step::Cancel(typed),
+        });
+        self.generation += 1;
+        self.advance(code, time, &mut steps);
+        steps
+    }
+
+    /// Nothing else was typed in time. If a sequence ends here, that's
+    /// the one that was meant.
+    fn timeout(&mut self, generation: u64) -> Vec<Step> {
+        if generation != self.generation || self.typed.is_empty() {
+            return Vec::new();
+        }
+        let action = self.current().action;
+        let typed = std::mem::take(&mut self.typed);
+        match action {
+            Some(id) => vec![Step::Fire(id)],
+            None => vec![Step::Cancel(typed)],
+        }
+    }
+}
+
+impl UserData for SequenceEngine {}
+
+/// A bound sequence, handed back to Lua so that it can be unbound.
+#[derive(Debug)]
+pub struct SequenceHandle {
+    device: String,
+    codes: Vec<String>,
+    id: i64,
+}
+
+impl SequenceHandle {
+    fn engine<'lua>(
+        &self,
+        ctx: &rlua::Context<'lua>,
+    ) -> rlua::Result<Option<rlua::AnyUserData<'lua>>> {
+        ctx.named_registry_value::<str, rlua::Table>(SEQUENCES_NAME)?
+            .get(self.device.as_str())
+    }
+
+    /// Remove the sequence. Returns whether it was still bound.
+    pub fn unbind(&self, ctx: &rlua::Context) -> rlua::Result<bool> {
+        let engine_ud = match self.engine(ctx)? {
+            Some(engine_ud) => engine_ud,
+            None => return Ok(false),
+        };
+        let callbacks = engine_ud.get_user_value::<rlua::Table>()?;
+        if !callbacks.contains_key(self.id)? {
+            return Ok(false);
+        }
+        callbacks.set(self.id, rlua::Value::Nil)?;
+        let mut engine = engine_ud.borrow_mut::<SequenceEngine>()?;
+        engine.typed.clear();
+        engine.root.remove(&self.codes, self.id);
+        Ok(true)
+    }
+}
+
+impl UserData for SequenceHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("unbind", |ctx, this, ()| this.unbind(&ctx));
+        methods.add_method("is_bound", |ctx, this, ()| match this.engine(&ctx)? {
+            Some(engine_ud) => engine_ud
+                .get_user_value::<rlua::Table>()?
+                .contains_key(this.id),
+            None => Ok(false),
+        });
+    }
+}
+
+pub fn set_up_sequences(ctx: &rlua::Context) -> rlua::Result<()> {
+    ctx.set_named_registry_value(SEQUENCES_NAME, ctx.create_table()?)?;
+    ctx.set_named_registry_value(SEQUENCE_PROGRESS_HOOKS_NAME, ctx.create_table()?)?;
+    ctx.set_named_registry_value(SEQUENCE_CANCEL_HOOKS_NAME, ctx.create_table()?)?;
+    Ok(())
+}
+
+fn run_steps<'lua>(
+    ctx: &rlua::Context<'lua>,
+    engine_ud: &rlua::AnyUserData<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    steps: Vec<Step>,
+) -> rlua::Result<()> {
+    for step in steps {
+        match step {
+            Step::Progress {
+                typed,
+                next,
+                since,
+                timeout,
+                generation,
+            } => {
+                let on_timeout = ctx.create_function(handle_timeout)?.bind((
+                    engine_ud.clone(),
+                    dev_ud.clone(),
+                    generation,
+                ))?;
+                timers::start_from(ctx, since, timeout, rlua::Value::Function(on_timeout))?;
+                coroutines::run_hooks(
+                    ctx,
+                    SEQUENCE_PROGRESS_HOOKS_NAME,
+                    (dev_ud.clone(), typed, next),
+                )?;
+            }
+            Step::Cancel(typed) => {
+                coroutines::run_hooks(ctx, SEQUENCE_CANCEL_HOOKS_NAME, (dev_ud.clone(), typed))?;
+            }
+            Step::Fire(id) => {
+                let callback = engine_ud
+                    .get_user_value::<rlua::Table>()?
+                    .get::<_, rlua::Function>(id)?;
+                coroutines::spawn(ctx, callback, ())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Feed a key event in. Returns `true` if a sequence took the event, or
+/// nothing to let it through.
+fn handle_event<'lua>(
+    ctx: rlua::Context<'lua>,
+    (engine_ud, event): (rlua::AnyUserData<'lua>, rlua::Table<'lua>),
+) -> rlua::Result<Option<bool>> {
+    let code: String = event.get("code")?;
+    let steps = {
+        let mut engine = engine_ud.borrow_mut::<SequenceEngine>()?;
+        match event.get::<_, i32>("value")? {
+            1 => engine.press(&code, event.get("time")?),
+            0 => return Ok(took(engine.swallowed.remove(&code))),
+            _ => return Ok(took(engine.swallowed.contains(&code))),
+        }
+    };
+    let took_it = steps
+        .iter()
+        .any(|step| matches!(step, Step::Progress { .. } | Step::Fire(_)));
+    run_steps(&ctx, &engine_ud, &event.get("device")?, steps)?;
+    Ok(took(took_it))
+}
+
+/// `true` stops the event going anywhere else, but nil just lets it go
+/// on to the other bindings.
+fn took(took_it: bool) -> Option<bool> {
+    if took_it {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn handle_timeout<'lua>(
+    ctx: rlua::Context<'lua>,
+    (engine_ud, dev_ud, generation): (rlua::AnyUserData<'lua>, rlua::AnyUserData<'lua>, u64),
+) -> rlua::Result<()> {
+    let steps = engine_ud
+        .borrow_mut::<SequenceEngine>()?
+        .timeout(generation);
+    run_steps(&ctx, &engine_ud, &dev_ud, steps)
+}
+
+/// The device's engine, creating it and binding it to the device's keys
+/// if this is the first sequence on the device.
+fn engine_for<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    dev_name: &str,
+) -> rlua::Result<rlua::AnyUserData<'lua>> {
+    let engines = ctx.named_registry_value::<str, rlua::Table>(SEQUENCES_NAME)?;
+    if let Some(engine_ud) = engines.get::<_, Option<rlua::AnyUserData>>(dev_name)? {
+        return Ok(engine_ud);
+    }
+    let engine_ud = ctx.create_userdata(SequenceEngine::default())?;
+    engine_ud.set_user_value(ctx.create_table()?)?;
+    let on_event = ctx.create_function(handle_event)?.bind(engine_ud.clone())?;
+    // Observing, so that keys that aren't part of a sequence go on as
+    // usual
+    bindings::set_in_bindings_table(
+        ctx,
+        dev_ud,
+        &BindTarget::Type(EventType::EV_KEY),
+        on_event,
+        BindOptions {
+            full: true,
+            priority: bindings::FIRST_PRIORITY,
+            observe: true,
+            layer: None,
+        },
+    )?;
+    engines.set(dev_name, engine_ud.clone())?;
+    Ok(engine_ud)
+}
+
+/// Call `callback` when the given keys are pressed on the device one
+/// after another.
+pub fn bind<'lua>(
+    ctx: &rlua::Context<'lua>,
+    dev_ud: &rlua::AnyUserData<'lua>,
+    codes: Vec<String>,
+    callback: rlua::Function<'lua>,
+    options: SequenceOptions,
+) -> rlua::Result<SequenceHandle> {
+    if codes.is_empty() {
+        return Err(SequenceError::Empty.into());
+    }
+    let codes = codes
+        .into_iter()
+        .map(|code| {
+            let event_code =
+                evdev_util::event_code_from_str(code.clone()).map_err(SequenceError::from)?;
+            if evdev_util::event_type_of(&event_code) != Some(EventType::EV_KEY) {
+                return Err(SequenceError::NotAKey(code));
+            }
+            Ok(event_code.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let dev_name = bindings::device_key(&dev_ud.borrow::<DeviceContext>()?)?;
+    let engine_ud = engine_for(ctx, dev_ud, &dev_name)?;
+    let id = bindings::next_binding_id(ctx)?;
+    engine_ud
+        .get_user_value::<rlua::Table>()?
+        .set(id, callback)?;
+    engine_ud
+        .borrow_mut::<SequenceEngine>()?
+        .root
+        .insert(&codes, id, options.timeout);
+    Ok(SequenceHandle {
+        device: dev_name,
+        codes,
+        id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(codes: &[&str]) -> Vec<String> {
+        codes.iter().map(|code| code.to_string()).collect()
+    }
+
+    fn engine(sequences: &[(&[&str], i64)]) -> SequenceEngine {
+        let mut engine = SequenceEngine::default();
+        for (sequence, id) in sequences {
+            engine.root.insert(&codes(sequence), *id, DEFAULT_TIMEOUT);
+        }
+        engine
+    }
+
+    #[test]
+    fn node_waits_for_longest_timeout() {
+        let mut root = Node::default();
+        root.insert(&codes(&["KEY_A", "KEY_B"]), 1, 0.5);
+        root.insert(&codes(&["KEY_A", "KEY_C"]), 2, 2.0);
+        let a = root.get(&codes(&["KEY_A"])).unwrap();
+        assert_eq!(a.timeout(), 2.0);
+        assert_eq!(a.children.keys().collect::<Vec<_>>(), ["KEY_B", "KEY_C"]);
+        assert_eq!(a.action, None);
+        assert_eq!(
+            root.get(&codes(&["KEY_A", "KEY_C"])).unwrap().action,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn node_prunes_removed_sequences() {
+        let mut root = Node::default();
+        root.insert(&codes(&["KEY_A", "KEY_B"]), 1, 0.5);
+        root.insert(&codes(&["KEY_A"]), 2, 1.0);
+        assert!(!root.remove(&codes(&["KEY_A", "KEY_B"]), 1));
+        assert!(root.get(&codes(&["KEY_A", "KEY_B"])).is_none());
+        assert_eq!(root.get(&codes(&["KEY_A"])).unwrap().timeout(), 1.0);
+        assert!(root.remove(&codes(&["KEY_A"]), 2));
+        assert!(root.children.is_empty());
+    }
+
+    #[test]
+    fn press_fires_complete_sequence() {
+        let mut engine = engine(&[(&["KEY_A", "KEY_B"], 1)]);
+        assert_eq!(
+            engine.press("KEY_A", 10.0),
+            vec![Step::Progress {
+                typed: codes(&["KEY_A"]),
+                next: codes(&["KEY_B"]),
+                since: 10.0,
+                timeout: DEFAULT_TIMEOUT,
+                generation: 1,
+            }]
+        );
+        assert_eq!(engine.press("KEY_B", 10.5), vec![Step::Fire(1)]);
+        assert!(engine.typed.is_empty());
+        assert!(engine.swallowed.contains("KEY_A") && engine.swallowed.contains("KEY_B"));
+    }
+
+    #[test]
+    fn press_ignores_keys_outside_sequences() {
+        let mut engine = engine(&[(&["KEY_A", "KEY_B"], 1)]);
+        assert!(engine.press("KEY_C", 0.0).is_empty());
+        assert!(engine.swallowed.is_empty());
+    }
+
+    #[test]
+    fn wrong_key_after_complete_prefix_fires_it() {
+        let mut engine = engine(&[(&["KEY_A"], 1), (&["KEY_A", "KEY_B"], 2)]);
+        engine.press("KEY_A", 0.0);
+        assert_eq!(engine.press("KEY_C", 0.1), vec![Step::Fire(1)]);
+        assert!(engine.typed.is_empty());
+    }
+
+    #[test]
+    fn wrong_key_cancels_and_can_start_again() {
+        let mut engine = engine(&[(&["KEY_A", "KEY_B"], 1)]);
+        engine.press("KEY_A", 0.0);
+        assert_eq!(
+            engine.press("KEY_C", 0.1),
+            vec![Step::Cancel(codes(&["KEY_A"]))]
+        );
+        engine.press("KEY_A", 0.2);
+        assert_eq!(
+            engine.press("KEY_A", 0.3),
+            vec![
+                Step::Cancel(codes(&["KEY_A"])),
+                Step::Progress {
+                    typed: codes(&["KEY_A"]),
+                    next: codes(&["KEY_B"]),
+                    since: 0.3,
+                    timeout: DEFAULT_TIMEOUT,
+                    generation: 5,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn timeout_fires_shorter_sequence() {
+        let mut engine = engine(&[(&["KEY_A"], 1), (&["KEY_A", "KEY_B"], 2)]);
+        engine.press("KEY_A", 0.0);
+        assert_eq!(engine.timeout(engine.generation), vec![Step::Fire(1)]);
+        assert!(engine.typed.is_empty());
+    }
+
+    #[test]
+    fn timeout_cancels_unfinished_sequence() {
+        let mut engine = engine(&[(&["KEY_A", "KEY_B", "KEY_C"], 1)]);
+        engine.press("KEY_A", 0.0);
+        engine.press("KEY_B", 0.1);
+        assert_eq!(
+            engine.timeout(engine.generation),
+            vec![Step::Cancel(codes(&["KEY_A", "KEY_B"]))]
+        );
+    }
+
+    #[test]
+    fn stale_timeout_does_nothing() {
+        let mut engine = engine(&[(&["KEY_A", "KEY_B", "KEY_C"], 1)]);
+        engine.press("KEY_A", 0.0);
+        let stale = engine.generation;
+        engine.press("KEY_B", 0.1);
+        assert!(engine.timeout(stale).is_empty());
+        assert_eq!(engine.typed, codes(&["KEY_A", "KEY_B"]));
+    }
+}
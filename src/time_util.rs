@@ -22,7 +22,7 @@ impl Clock {
     }
 }
 
-#[derive(Copy, Clone, Add, Sub, Mul)]
+#[derive(Copy, Clone, PartialEq, PartialOrd, Add, Sub, Mul)]
 /// Opaque duration struct. Usually used to represent instants by
 /// holding seconds-since-epoch on CLOCK_MONOTONIC, but since we're
 /// operating with two different c-ish time structs (evdev timevals
@@ -85,7 +85,7 @@ impl From<TimeSpec> for Time {
 
 impl From<TimeVal> for Time {
     fn from(tv: TimeVal) -> Self {
-        Time(tv.tv_sec as f64 + ((tv.tv_usec / 10_000) as f64))
+        Time(tv.tv_sec as f64 + (tv.tv_usec as f64 / 1_000_000f64))
     }
 }
 
@@ -99,7 +99,7 @@ impl From<Time> for TimeVal {
     fn from(time: Time) -> Self {
         TimeVal {
             tv_sec: time.0.floor() as i64,
-            tv_usec: (time.0.fract() * 10_000f64) as i64,
+            tv_usec: (time.0.fract() * 1_000_000f64) as i64,
         }
     }
 }
@@ -115,3 +115,34 @@ impl From<Time> for Duration {
         Duration::from_secs_f64(time.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeval_microseconds_to_time() {
+        let tv = TimeVal {
+            tv_sec: 3,
+            tv_usec: 250_000,
+        };
+        assert_eq!(f64::from(Time::from(tv)), 3.25);
+    }
+
+    #[test]
+    fn time_to_timeval_microseconds() {
+        let tv = TimeVal::from(Time::from(12.5));
+        assert_eq!(tv.tv_sec, 12);
+        assert_eq!(tv.tv_usec, 500_000);
+    }
+
+    #[test]
+    fn timeval_round_trip() {
+        let tv = TimeVal::from(Time::from(TimeVal {
+            tv_sec: 7,
+            tv_usec: 750_000,
+        }));
+        assert_eq!(tv.tv_sec, 7);
+        assert_eq!(tv.tv_usec, 750_000);
+    }
+}
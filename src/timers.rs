@@ -0,0 +1,166 @@
+use crate::coroutines;
+use crate::event_loop::{self, Token};
+use crate::time_util::{self, Time};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use rlua::{UserData, UserDataMethods};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+pub const TIMERS_NAME: &str = "timers";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TimerError {
+    #[error("`{0}` is not a valid number of seconds for a timer")]
+    InvalidDuration(f64),
+    #[error(transparent)]
+    Timerfd(#[from] nix::errno::Errno),
+}
+
+impl From<TimerError> for rlua::Error {
+    fn from(e: TimerError) -> rlua::Error {
+        rlua::Error::external(e)
+    }
+}
+
+pub type TimerResult<T> = Result<T, TimerError>;
+
+/// A timerfd in the main event loop. What to do when it fires is kept
+/// in the userdata's user value: either a function to call or a
+/// suspended callback to resume.
+#[derive(Debug)]
+pub struct Timer {
+    /// None once the timer has been cancelled or has fired for the
+    /// last time
+    timerfd: Option<TimerFd>,
+    repeating: bool,
+}
+
+impl UserData for Timer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function("cancel", |ctx, this: rlua::AnyUserData| cancel(&ctx, &this));
+
+        methods.add_method("is_active", |_, this, _: ()| Ok(this.timerfd.is_some()));
+    }
+}
+
+fn timespec(seconds: f64) -> TimerResult<TimeSpec> {
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(TimerError::InvalidDuration(seconds));
+    }
+    // An all-zero expiration disarms a timerfd, so "right now" has
+    // to be a little bit later than that
+    Ok(TimeSpec::from(Time::from(seconds.max(1e-9))))
+}
+
+pub fn set_up_timers(ctx: &rlua::Context) -> rlua::Result<()> {
+    ctx.set_named_registry_value(TIMERS_NAME, ctx.create_table()?)
+}
+
+/// Start a timer that runs `action` after `delay` seconds and then,
+/// if `interval` is given, every `interval` seconds after that.
+pub fn start<'lua>(
+    ctx: &rlua::Context<'lua>,
+    delay: f64,
+    interval: Option<f64>,
+    action: rlua::Value<'lua>,
+) -> rlua::Result<rlua::AnyUserData<'lua>> {
+    let expiration = match interval {
+        None => Expiration::OneShot(timespec(delay)?),
+        Some(interval) if interval > 0.0 => {
+            Expiration::IntervalDelayed(timespec(delay)?, timespec(interval)?)
+        }
+        Some(interval) => return Err(TimerError::InvalidDuration(interval).into()),
+    };
+    let timerfd = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+    )
+    .map_err(TimerError::from)?;
+    timerfd
+        .set(expiration, TimerSetTimeFlags::empty())
+        .map_err(TimerError::from)?;
+
+    let fd = timerfd.as_raw_fd();
+    event_loop::watch(ctx, fd, Token::Timer(fd))?;
+    let timer_ud = ctx.create_userdata(Timer {
+        timerfd: Some(timerfd),
+        repeating: interval.is_some(),
+    })?;
+    timer_ud.set_user_value(action)?;
+    // The timers table keeps active timers alive even if the script
+    // drops its handle
+    ctx.named_registry_value::<str, rlua::Table>(TIMERS_NAME)?
+        .set(fd, timer_ud.clone())?;
+    Ok(timer_ud)
+}
+
+/// Start a one-off timer that runs `action` `secs` seconds after
+/// `since`. Timing from when an event happened, not from when we got
+/// around to reading it, keeps timeouts from stretching when we're busy.
+///
+/// This is what gestures, chords and sequences time out with. Their
+/// times are all seconds on the same clock as event timestamps. Each
+/// keeps a generation count that it bumps whenever it starts waiting
+/// for something new and binds into `action`, so that a timer from an
+/// earlier wait can tell it's out of date. Anything they need from Lua,
+/// like callbacks and bindings, is kept in their userdata's user value.
+pub fn start_from<'lua>(
+    ctx: &rlua::Context<'lua>,
+    since: f64,
+    secs: f64,
+    action: rlua::Value<'lua>,
+) -> rlua::Result<rlua::AnyUserData<'lua>> {
+    let now = f64::from(time_util::CLOCK.now().map_err(rlua::Error::external)?);
+    start(ctx, (secs - (now - since)).max(0.0), None, action)
+}
+
+pub fn cancel<'lua>(
+    ctx: &rlua::Context<'lua>,
+    timer_ud: &rlua::AnyUserData<'lua>,
+) -> rlua::Result<()> {
+    let timerfd = timer_ud.borrow_mut::<Timer>()?.timerfd.take();
+    if let Some(timerfd) = timerfd {
+        let fd = timerfd.as_raw_fd();
+        event_loop::unwatch(ctx, fd)?;
+        ctx.named_registry_value::<str, rlua::Table>(TIMERS_NAME)?
+            .set(fd, rlua::Value::Nil)?;
+    }
+    Ok(())
+}
+
+pub fn any_active(ctx: &rlua::Context) -> rlua::Result<bool> {
+    let timers = ctx.named_registry_value::<str, rlua::Table>(TIMERS_NAME)?;
+    Ok(timers.pairs::<RawFd, rlua::AnyUserData>().next().is_some())
+}
+
+/// Called by the event loop when a timer's fd becomes readable.
+pub fn fire(ctx: &rlua::Context, fd: RawFd) -> rlua::Result<()> {
+    let timers = ctx.named_registry_value::<str, rlua::Table>(TIMERS_NAME)?;
+    // It may have been cancelled since epoll_wait returned
+    let timer_ud = match timers.get::<_, Option<rlua::AnyUserData>>(fd)? {
+        Some(timer_ud) => timer_ud,
+        None => return Ok(()),
+    };
+    let repeating = {
+        let timer = timer_ud.borrow::<Timer>()?;
+        let timerfd = match &timer.timerfd {
+            Some(timerfd) => timerfd,
+            None => return Ok(()),
+        };
+        match timerfd.wait() {
+            Ok(()) => (),
+            Err(nix::errno::Errno::EAGAIN) => return Ok(()),
+            Err(e) => return Err(TimerError::from(e).into()),
+        }
+        timer.repeating
+    };
+    if !repeating {
+        cancel(ctx, &timer_ud)?;
+    }
+    match timer_ud.get_user_value::<rlua::Value>()? {
+        rlua::Value::Function(callback) => coroutines::spawn(ctx, callback, ())?,
+        rlua::Value::Thread(thread) => coroutines::resume(ctx, thread, ())?,
+        other => panic!("timer had the wrong kind of action: `{:?}`", other),
+    };
+    Ok(())
+}
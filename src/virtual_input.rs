@@ -1,10 +1,15 @@
 use crate::interpolator::Interpolator;
 use crate::time_util::{Clock, Time};
-use evdev_rs::enums::{BusType, EventCode, EventType, EV_KEY, EV_REL, EV_SYN};
-use evdev_rs::{DeviceWrapper, InputEvent, UInputDevice, UninitDevice};
-use rlua::prelude::LuaError;
-use rlua::{UserData, UserDataMethods};
-use std::sync::mpsc::{channel, Receiver, SendError, Sender, TryRecvError};
+use evdev_rs::enums::{BusType, EventCode, EventType, EV_ABS, EV_KEY, EV_REL, EV_SYN};
+use evdev_rs::{AbsInfo, DeviceWrapper, EnableCodeData, InputEvent, UInputDevice, UninitDevice};
+use lazy_static::lazy_static;
+use rlua::prelude::{LuaContext, LuaError, LuaResult, LuaValue};
+use rlua::{FromLua, UserData, UserDataMethods};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
@@ -20,6 +25,18 @@ pub enum InputError {
     WorkerDisconnected(#[from] SendError<WorkerMessage>),
     #[error("`{0}` is not a valid evdev key code")]
     InvalidKeyCode(String),
+    #[error("`{0}` is not a valid evdev absolute axis code")]
+    InvalidAxisCode(String),
+    #[error("`{0}` is an absolute axis, so it needs a range; put it in `abs` instead of `codes`")]
+    AxisInCodes(String),
+    #[error(transparent)]
+    InvalidEventCode(#[from] evdev_util::CodeFromStrError),
+    #[error("`{0}` is not a valid tick rate")]
+    InvalidTickRate(f64),
+    #[error("`{0}` is not a valid turbo rate")]
+    InvalidTurboRate(f64),
+    #[error("`{0}` is not a valid duty cycle, which has to be between 0 and 1")]
+    InvalidDutyCycle(f64),
 }
 
 impl From<InputError> for LuaError {
@@ -30,39 +47,72 @@ impl From<InputError> for LuaError {
 
 pub type InputResult<T> = Result<T, InputError>;
 
-// max time between updates
-const MOUSE_PERIOD: Duration = Duration::from_millis(1000 / 120);
+// updates per second while something is moving, unless the device
+// says otherwise
+const DEFAULT_TICK_RATE: f64 = 120.0;
 
-#[derive(Copy, Clone, Debug)]
-pub enum InputOp {
-    Button { key: EV_KEY, value: i32 },
-    XAbs { x: f64 },
-    YAbs { y: f64 },
-    XRel { dx: f64 },
-    YRel { dy: f64 },
-    XVel { dxdt: f64 },
-    YVel { dydt: f64 },
+// fraction of each turbo pulse that the key is held down for, unless
+// the script says otherwise
+const DEFAULT_DUTY_CYCLE: f64 = 0.5;
+
+lazy_static! {
+    /// Device nodes of the uinput devices we've created, so that we
+    /// don't pick them up as input devices ourselves.
+    static ref OWN_DEVNODES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct WorkerMessage(Time, InputOp);
+pub fn is_own_devnode(path: &Path) -> bool {
+    OWN_DEVNODES.lock().unwrap().contains(path)
+}
 
-#[derive(Debug)]
-pub struct VirtualInput {
-    sender: Sender<WorkerMessage>,
-    clock: Clock,
+pub fn note_own_device(device: &UInputDevice) {
+    if let Some(devnode) = device.devnode() {
+        OWN_DEVNODES.lock().unwrap().insert(PathBuf::from(devnode));
+    }
 }
 
-impl VirtualInput {
-    pub fn new(clock: Clock) -> InputResult<Self> {
-        let dev = UninitDevice::new().unwrap();
-        dev.set_name("evdotool virtual input");
-        dev.set_bustype(BusType::BUS_USB as u16);
-        dev.set_vendor_id(0xabcd);
-        dev.set_product_id(0xefef);
+/// Range and tuning of an absolute axis on a virtual device
+#[derive(Copy, Clone, Debug)]
+pub struct AxisConfig {
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
 
-        // Enable keyboard keys and mouse buttons
+impl<'lua> FromLua<'lua> for AxisConfig {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        let t = match lua_value {
+            LuaValue::Table(t) => t,
+            _ => return Err(LuaError::external("axis options must be a table")),
+        };
+        Ok(AxisConfig {
+            minimum: t.get("minimum")?,
+            maximum: t.get("maximum")?,
+            fuzz: t.get::<_, Option<i32>>("fuzz")?.unwrap_or(0),
+            flat: t.get::<_, Option<i32>>("flat")?.unwrap_or(0),
+            resolution: t.get::<_, Option<i32>>("resolution")?.unwrap_or(0),
+        })
+    }
+}
+
+/// Everything needed to create a virtual device.
+#[derive(Clone, Debug)]
+pub struct VirtualInputConfig {
+    pub name: String,
+    pub bustype: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub codes: Vec<EventCode>,
+    pub abs: Vec<(EV_ABS, AxisConfig)>,
+    /// Updates per second while the mouse or a scroll wheel is moving
+    pub tick_rate: f64,
+}
 
+impl Default for VirtualInputConfig {
+    /// A combined keyboard and mouse, which is what `INPUT` is.
+    fn default() -> Self {
         // For some reason wlroots refuses to recognize our device if
         // we have too many events enabled, so we have to be
         // selective. >_<
@@ -70,57 +120,357 @@ impl VirtualInput {
         // We have to be sure we have these enabled, otherwise wlroots
         // or sway won't think we're a mouse and will ignore our axis
         // movements.
-        dev.enable_event_type(&EventType::EV_KEY)?;
-        dev.enable_event_code(&EventCode::EV_KEY(EV_KEY::BTN_LEFT), None)?;
-        dev.enable_event_code(&EventCode::EV_KEY(EV_KEY::BTN_RIGHT), None)?;
+        let mouse_buttons = vec![
+            EventCode::EV_KEY(EV_KEY::BTN_LEFT),
+            EventCode::EV_KEY(EV_KEY::BTN_RIGHT),
+        ];
 
         // Enable as much of the keyboard stuff as we can.
-        evdev_util::codes_for(EventType::EV_KEY)
-            .unwrap()
-            .take(200)
-            .try_for_each(|code| dev.enable_event_code(&code, None))?;
+        let keys = evdev_util::codes_for(EventType::EV_KEY).unwrap().take(200);
+
+        // Enable relative mouse movements and scroll wheels
+        let rel = vec![
+            EventCode::EV_REL(EV_REL::REL_X),
+            EventCode::EV_REL(EV_REL::REL_Y),
+            EventCode::EV_REL(EV_REL::REL_WHEEL),
+            EventCode::EV_REL(EV_REL::REL_HWHEEL),
+            EventCode::EV_REL(EV_REL::REL_WHEEL_HI_RES),
+            EventCode::EV_REL(EV_REL::REL_HWHEEL_HI_RES),
+        ];
+
+        Self {
+            name: String::from("evdotool virtual input"),
+            bustype: BusType::BUS_USB as u16,
+            vendor_id: 0xabcd,
+            product_id: 0xefef,
+            codes: mouse_buttons.into_iter().chain(keys).chain(rel).collect(),
+            abs: Vec::new(),
+            tick_rate: DEFAULT_TICK_RATE,
+        }
+    }
+}
+
+impl VirtualInputConfig {
+    /// An Xbox 360 style controller, which is what most games expect.
+    pub fn gamepad() -> Self {
+        let buttons = [
+            EV_KEY::BTN_SOUTH,
+            EV_KEY::BTN_EAST,
+            EV_KEY::BTN_NORTH,
+            EV_KEY::BTN_WEST,
+            EV_KEY::BTN_TL,
+            EV_KEY::BTN_TR,
+            EV_KEY::BTN_SELECT,
+            EV_KEY::BTN_START,
+            EV_KEY::BTN_MODE,
+            EV_KEY::BTN_THUMBL,
+            EV_KEY::BTN_THUMBR,
+        ];
+        let stick = AxisConfig {
+            minimum: -32768,
+            maximum: 32767,
+            fuzz: 16,
+            flat: 128,
+            resolution: 0,
+        };
+        let trigger = AxisConfig {
+            minimum: 0,
+            maximum: 255,
+            fuzz: 0,
+            flat: 0,
+            resolution: 0,
+        };
+        let hat = AxisConfig {
+            minimum: -1,
+            maximum: 1,
+            fuzz: 0,
+            flat: 0,
+            resolution: 0,
+        };
+        Self {
+            name: String::from("evdotool virtual gamepad"),
+            bustype: BusType::BUS_USB as u16,
+            vendor_id: 0x045e,
+            product_id: 0x028e,
+            codes: buttons.iter().map(|key| EventCode::EV_KEY(*key)).collect(),
+            abs: vec![
+                (EV_ABS::ABS_X, stick),
+                (EV_ABS::ABS_Y, stick),
+                (EV_ABS::ABS_RX, stick),
+                (EV_ABS::ABS_RY, stick),
+                (EV_ABS::ABS_Z, trigger),
+                (EV_ABS::ABS_RZ, trigger),
+                (EV_ABS::ABS_HAT0X, hat),
+                (EV_ABS::ABS_HAT0Y, hat),
+            ],
+            tick_rate: DEFAULT_TICK_RATE,
+        }
+    }
+
+    /// Replace parts of this config with the options in the given
+    /// table. `codes` replaces the whole list of codes, while each
+    /// entry in `abs` replaces or adds a single axis.
+    pub fn with_overrides(mut self, t: &rlua::Table) -> LuaResult<Self> {
+        if let Some(name) = t.get::<_, Option<String>>("name")? {
+            self.name = name;
+        }
+        if let Some(bustype) = t.get::<_, Option<u16>>("bustype")? {
+            self.bustype = bustype;
+        }
+        if let Some(vendor_id) = t.get::<_, Option<u16>>("vendor_id")? {
+            self.vendor_id = vendor_id;
+        }
+        if let Some(product_id) = t.get::<_, Option<u16>>("product_id")? {
+            self.product_id = product_id;
+        }
+        if let Some(codes) = t.get::<_, Option<Vec<String>>>("codes")? {
+            self.codes = codes
+                .into_iter()
+                .map(evdev_util::event_code_from_str)
+                .collect::<Result<_, _>>()
+                .map_err(InputError::from)?;
+            // libevdev refuses absolute axes without an AbsInfo, and
+            // the error it gives doesn't say why
+            if let Some(axis) = self
+                .codes
+                .iter()
+                .find(|code| matches!(code, EventCode::EV_ABS(_)))
+            {
+                return Err(InputError::AxisInCodes(axis.to_string()).into());
+            }
+        }
+        if let Some(abs) = t.get::<_, Option<HashMap<String, AxisConfig>>>("abs")? {
+            for (axis, axis_config) in abs.into_iter() {
+                // <EV_ABS as FromStr>::Err is just (), so we can't
+                // keep it around
+                let code: EV_ABS = axis
+                    .parse()
+                    .map_err(|_| InputError::InvalidAxisCode(axis))?;
+                self.abs.retain(|(existing, _)| *existing != code);
+                self.abs.push((code, axis_config));
+            }
+        }
+        if let Some(tick_rate) = t.get::<_, Option<f64>>("tick_rate")? {
+            if tick_rate.is_finite() && tick_rate > 0.0 {
+                self.tick_rate = tick_rate;
+            } else {
+                return Err(InputError::InvalidTickRate(tick_rate).into());
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl<'lua> FromLua<'lua> for VirtualInputConfig {
+    /// Anything that isn't specified is the same as for `INPUT`.
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::Nil => Ok(Self::default()),
+            LuaValue::Table(t) => Self::default().with_overrides(&t),
+            _ => Err(LuaError::external("virtual device options must be a table")),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum InputOp {
+    Button {
+        key: EV_KEY,
+        value: i32,
+    },
+    Axis {
+        axis: EV_ABS,
+        value: i32,
+    },
+    XAbs {
+        x: f64,
+    },
+    YAbs {
+        y: f64,
+    },
+    XRel {
+        dx: f64,
+    },
+    YRel {
+        dy: f64,
+    },
+    XVel {
+        dxdt: f64,
+    },
+    YVel {
+        dydt: f64,
+    },
+    Scroll {
+        dy: f64,
+        dx: f64,
+    },
+    ScrollVel {
+        dydt: f64,
+        dxdt: f64,
+    },
+    Turbo {
+        key: EV_KEY,
+        hz: f64,
+        duty: f64,
+        enabled: bool,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct WorkerMessage(Time, InputOp);
 
-        // Enable relative mouse movements
-        dev.enable_event_type(&EventType::EV_REL)?;
-        dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_X), None)?;
-        dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_Y), None)?;
+/// A message waiting for its time to come, along with a sequence
+/// number so that messages for the same time stay in order.
+#[derive(Debug)]
+struct Scheduled(u64, WorkerMessage);
+
+impl Scheduled {
+    fn time(&self) -> Time {
+        (self.1).0
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap, so this is backwards to put the
+        // earliest message on top
+        other
+            .time()
+            .partial_cmp(&self.time())
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.0.cmp(&self.0))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+/// The last value the worker emitted for each key and absolute axis,
+/// shared so that scripts can ask about it.
+type EmittedState = Arc<Mutex<HashMap<EventCode, i32>>>;
+
+fn note_emitted(state: &EmittedState, code: EventCode, value: i32) {
+    // Codes the device doesn't have never reach anyone, so they don't
+    // count
+    if let Some(emitted) = state.lock().unwrap().get_mut(&code) {
+        *emitted = value;
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtualInput {
+    sender: Sender<WorkerMessage>,
+    clock: Clock,
+    state: EmittedState,
+}
+
+impl VirtualInput {
+    pub fn new(clock: Clock, config: &VirtualInputConfig) -> InputResult<Self> {
+        let dev = UninitDevice::new().unwrap();
+        dev.set_name(&config.name);
+        dev.set_bustype(config.bustype);
+        dev.set_vendor_id(config.vendor_id);
+        dev.set_product_id(config.product_id);
+
+        // Enabling a code also enables its event type
+        for code in config.codes.iter() {
+            dev.enable_event_code(code, None)?;
+        }
+        for (axis, axis_config) in config.abs.iter() {
+            let info = AbsInfo {
+                value: 0,
+                minimum: axis_config.minimum,
+                maximum: axis_config.maximum,
+                fuzz: axis_config.fuzz,
+                flat: axis_config.flat,
+                resolution: axis_config.resolution,
+            };
+            dev.enable_event_code(
+                &EventCode::EV_ABS(*axis),
+                Some(EnableCodeData::AbsInfo(info)),
+            )?;
+        }
 
         // Enable syn reports
         dev.enable_event_code(&EventCode::EV_SYN(EV_SYN::SYN_REPORT), None)?;
 
         // Create the UInputDevice
         let device = UInputDevice::create_from_device(&dev)?;
+        note_own_device(&device);
 
         // Create channels
         let (sender, receiver) = channel();
 
+        // Keys start out released and axes at 0, as far as anyone
+        // listening knows
+        let mut initial_state = HashMap::new();
+        for code in config.codes.iter() {
+            if let EventCode::EV_KEY(_) = code {
+                initial_state.insert(*code, 0);
+            }
+        }
+        for (axis, _) in config.abs.iter() {
+            initial_state.insert(EventCode::EV_ABS(*axis), 0);
+        }
+        let state: EmittedState = Arc::new(Mutex::new(initial_state));
+
         // Create and start the worker
         let mut worker = VirtualInputWorker {
             device,
-            period: MOUSE_PERIOD,
+            period: Duration::from_secs_f64(1.0 / config.tick_rate),
+            next_tick: None,
+            scheduled: BinaryHeap::new(),
+            next_seq: 0,
+            turbos: HashMap::new(),
             receiver,
             x_interp: None,
             y_interp: None,
+            vwheel: Wheel::new(EV_REL::REL_WHEEL_HI_RES, EV_REL::REL_WHEEL),
+            hwheel: Wheel::new(EV_REL::REL_HWHEEL_HI_RES, EV_REL::REL_HWHEEL),
             clock,
+            state: state.clone(),
         };
         thread::spawn(move || {
             worker.run();
         });
 
         // Return ourself
-        Ok(VirtualInput { sender, clock })
-    }
-
-    fn time_or_now(&self, time: Option<Time>) -> InputResult<Time> {
-        Ok(match time {
-            Some(t) => t,
-            None => self.clock.now()?,
+        Ok(VirtualInput {
+            sender,
+            clock,
+            state,
         })
     }
 
     fn send(&self, time: Option<Time>, op: InputOp) -> InputResult<()> {
-        self.sender
-            .send(WorkerMessage(self.time_or_now(time)?, op))?;
+        let now = self.clock.now()?;
+        let time = time.unwrap_or(now);
+        // The worker only gets round to emitting this after we return,
+        // so note keys and axes that are due now ourselves, so that the
+        // script sees its own changes straight away
+        if time <= now {
+            match op {
+                InputOp::Button { key, value } => {
+                    note_emitted(&self.state, EventCode::EV_KEY(key), value)
+                }
+                InputOp::Axis { axis, value } => {
+                    note_emitted(&self.state, EventCode::EV_ABS(axis), value)
+                }
+                _ => (),
+            }
+        }
+        self.sender.send(WorkerMessage(time, op))?;
         Ok(())
     }
 
@@ -128,6 +478,10 @@ impl VirtualInput {
         self.send(time, InputOp::Button { key, value })
     }
 
+    pub fn set_axis(&self, time: Option<Time>, axis: EV_ABS, value: i32) -> InputResult<()> {
+        self.send(time, InputOp::Axis { axis, value })
+    }
+
     pub fn set_x_vel(&self, time: Option<Time>, dxdt: f64) -> InputResult<()> {
         self.send(time, InputOp::XVel { dxdt })
     }
@@ -144,6 +498,14 @@ impl VirtualInput {
         self.send(time, InputOp::YRel { dy })
     }
 
+    pub fn scroll(&self, time: Option<Time>, dy: f64, dx: f64) -> InputResult<()> {
+        self.send(time, InputOp::Scroll { dy, dx })
+    }
+
+    pub fn set_scroll_vel(&self, time: Option<Time>, dydt: f64, dxdt: f64) -> InputResult<()> {
+        self.send(time, InputOp::ScrollVel { dydt, dxdt })
+    }
+
     pub fn set_x(&self, time: Option<Time>, x: f64) -> InputResult<()> {
         self.send(time, InputOp::XAbs { x })
     }
@@ -151,6 +513,54 @@ impl VirtualInput {
     pub fn set_y(&self, time: Option<Time>, y: f64) -> InputResult<()> {
         self.send(time, InputOp::YAbs { y })
     }
+
+    pub fn turbo(
+        &self,
+        time: Option<Time>,
+        key: EV_KEY,
+        hz: f64,
+        duty: f64,
+        enabled: bool,
+    ) -> InputResult<()> {
+        if enabled {
+            if !hz.is_finite() || hz <= 0.0 {
+                return Err(InputError::InvalidTurboRate(hz));
+            }
+            // Either end would mean the key never changes
+            if !(duty > 0.0 && duty < 1.0) {
+                return Err(InputError::InvalidDutyCycle(duty));
+            }
+        }
+        self.send(
+            time,
+            InputOp::Turbo {
+                key,
+                hz,
+                duty,
+                enabled,
+            },
+        )
+    }
+
+    /// The last value we emitted, or were asked to emit now, for a key
+    /// or absolute axis, or None if this device doesn't have it. Events
+    /// scheduled for the future don't count until they've been sent.
+    pub fn event_value(&self, code: &EventCode) -> Option<i32> {
+        self.state.lock().unwrap().get(code).copied()
+    }
+
+    /// Every key or button we've pressed and not yet released, in code
+    /// order.
+    pub fn pressed_keys(&self) -> Vec<EventCode> {
+        let state = self.state.lock().unwrap();
+        let mut pressed: Vec<EventCode> = state
+            .iter()
+            .filter(|(code, value)| matches!(code, EventCode::EV_KEY(_)) && **value != 0)
+            .map(|(code, _)| *code)
+            .collect();
+        pressed.sort_by_key(evdev_rs::util::event_code_to_int);
+        pressed
+    }
 }
 
 impl UserData for VirtualInput {
@@ -167,6 +577,20 @@ impl UserData for VirtualInput {
         methods.add_method("move_y", |_, this, (dy, t): (f64, Option<Time>)| {
             this.move_y(t, dy).map_err(LuaError::external)
         });
+        methods.add_method(
+            "scroll",
+            |_, this, (dy, dx, t): (f64, Option<f64>, Option<Time>)| {
+                this.scroll(t, dy, dx.unwrap_or(0.0))
+                    .map_err(LuaError::external)
+            },
+        );
+        methods.add_method(
+            "set_scroll_vel",
+            |_, this, (dydt, dxdt, t): (f64, Option<f64>, Option<Time>)| {
+                this.set_scroll_vel(t, dydt, dxdt.unwrap_or(0.0))
+                    .map_err(LuaError::external)
+            },
+        );
         methods.add_method("set_x", |_, this, (x, t): (f64, Option<Time>)| {
             this.set_x(t, x).map_err(LuaError::external)
         });
@@ -186,6 +610,191 @@ impl UserData for VirtualInput {
                 this.button(t, key, value).map_err(LuaError::external)
             },
         );
+        methods.add_method(
+            "turbo",
+            |_,
+             this,
+             (key_string, hz, enabled, duty, t): (
+                String,
+                f64,
+                bool,
+                Option<f64>,
+                Option<Time>,
+            )| {
+                let key = key_string
+                    .parse()
+                    .map_err(|_| InputError::InvalidKeyCode(key_string))?;
+
+                this.turbo(t, key, hz, duty.unwrap_or(DEFAULT_DUTY_CYCLE), enabled)
+                    .map_err(LuaError::external)
+            },
+        );
+        methods.add_method("value", |_, this, code: String| {
+            Ok(this.event_value(&evdev_util::event_code_from_str(code).map_err(InputError::from)?))
+        });
+        methods.add_method("is_pressed", |_, this, code: String| {
+            let code = evdev_util::event_code_from_str(code).map_err(InputError::from)?;
+            Ok(this.event_value(&code).map_or(false, |value| value != 0))
+        });
+        methods.add_method("pressed_keys", |ctx, this, _: ()| {
+            ctx.create_sequence_from(this.pressed_keys().into_iter().map(|code| code.to_string()))
+        });
+        methods.add_method(
+            "set_axis",
+            |_, this, (axis_string, value, t): (String, i32, Option<Time>)| {
+                let axis = axis_string
+                    .parse()
+                    .map_err(|_| InputError::InvalidAxisCode(axis_string))?;
+
+                this.set_axis(t, axis, value).map_err(LuaError::external)
+            },
+        );
+    }
+}
+
+/// High-resolution scroll units per notch of a traditional wheel
+const HI_RES_PER_NOTCH: i32 = 120;
+
+/// A scroll wheel. We scroll in high-resolution units and emit a
+/// legacy notch event whenever enough of them add up in one
+/// direction, the same way a real high-resolution mouse does.
+#[derive(Debug)]
+struct Wheel {
+    hi_res_code: EV_REL,
+    notch_code: EV_REL,
+    interp: Option<Interpolator>,
+    /// dither error to handle fractional hi-res units
+    err: f64,
+    /// hi-res units that haven't added up to a whole notch yet
+    partial_notch: i32,
+}
+
+impl Wheel {
+    fn new(hi_res_code: EV_REL, notch_code: EV_REL) -> Self {
+        Self {
+            hi_res_code,
+            notch_code,
+            interp: None,
+            err: 0.0,
+            partial_notch: 0,
+        }
+    }
+
+    fn set_vel(&mut self, time: Time, notches_per_sec: f64) {
+        if notches_per_sec == 0.0 {
+            self.interp = None;
+        } else {
+            let interp = self.interp.get_or_insert_with(|| Interpolator::new(time));
+            interp.update(time, notches_per_sec * HI_RES_PER_NOTCH as f64);
+        }
+    }
+
+    /// Convert a movement in notches to hi-res units
+    fn dither(&mut self, notches: f64) -> i32 {
+        let hi_res = self.err + notches * HI_RES_PER_NOTCH as f64;
+        self.err = hi_res.fract();
+        hi_res.trunc() as i32
+    }
+
+    /// How far to scroll on this tick, in hi-res units
+    fn tick(&mut self, time: Time) -> i32 {
+        match &mut self.interp {
+            Some(interp) => interp.interpolate(time) as i32,
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, device: &UInputDevice, time: Time, hi_res: i32) -> InputResult<()> {
+        if hi_res == 0 {
+            return Ok(());
+        }
+        // Changing direction starts a new notch
+        if hi_res.signum() != self.partial_notch.signum() {
+            self.partial_notch = 0;
+        }
+        self.partial_notch += hi_res;
+        let notches = self.partial_notch / HI_RES_PER_NOTCH;
+        self.partial_notch -= notches * HI_RES_PER_NOTCH;
+
+        device.write_event(&InputEvent {
+            time: time.into(),
+            event_code: EventCode::EV_REL(self.hi_res_code),
+            value: hi_res,
+        })?;
+        if notches != 0 {
+            device.write_event(&InputEvent {
+                time: time.into(),
+                event_code: EventCode::EV_REL(self.notch_code),
+                value: notches,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// A key that the worker presses and releases over and over by
+/// itself. Every press and release is timed from when the turbo
+/// started, so they don't drift however late the worker wakes up.
+#[derive(Copy, Clone, Debug)]
+struct Turbo {
+    /// When the first press was, in seconds
+    start: f64,
+    /// Seconds from one press to the next
+    period: f64,
+    /// Fraction of each period the key is down for
+    duty: f64,
+    /// Presses sent so far, counting any that we skipped
+    presses: u64,
+    pressed: bool,
+}
+
+impl Turbo {
+    fn new(time: Time, hz: f64, duty: f64) -> Self {
+        Self {
+            start: f64::from(time),
+            period: 1.0 / hz,
+            duty,
+            presses: 0,
+            pressed: false,
+        }
+    }
+
+    fn next_change(&self) -> Time {
+        Time::from(if self.pressed {
+            self.start + self.period * ((self.presses - 1) as f64 + self.duty)
+        } else {
+            self.start + self.period * self.presses as f64
+        })
+    }
+
+    /// The next press or release, if it's due.
+    fn step(&mut self, now: Time) -> Option<(Time, i32)> {
+        if self.next_change() > now {
+            return None;
+        }
+        if !self.pressed {
+            // If we've fallen more than a whole period behind, skip the
+            // pulses we missed rather than sending them all at once
+            let current = ((f64::from(now) - self.start) / self.period).floor() as u64;
+            self.presses = self.presses.max(current);
+        }
+        let time = self.next_change();
+        if self.pressed {
+            self.pressed = false;
+            Some((time, 0))
+        } else {
+            self.presses += 1;
+            self.pressed = true;
+            Some((time, 1))
+        }
+    }
+}
+
+fn earliest(a: Option<Time>, b: Option<Time>) -> Option<Time> {
+    match (a, b) {
+        (Some(a), Some(b)) if b < a => Some(b),
+        (Some(a), _) => Some(a),
+        (None, b) => b,
     }
 }
 
@@ -193,38 +802,155 @@ impl UserData for VirtualInput {
 struct VirtualInputWorker {
     device: UInputDevice,
     period: Duration,
+    /// When we next need to send velocity movement, if anything is
+    /// moving
+    next_tick: Option<Time>,
+    /// Messages that aren't due yet
+    scheduled: BinaryHeap<Scheduled>,
+    next_seq: u64,
+    turbos: HashMap<EV_KEY, Turbo>,
     receiver: Receiver<WorkerMessage>,
     x_interp: Option<Interpolator>,
     y_interp: Option<Interpolator>,
+    vwheel: Wheel,
+    hwheel: Wheel,
     clock: Clock,
+    state: EmittedState,
 }
 
 impl VirtualInputWorker {
     fn run(&mut self) {
         loop {
-            std::thread::sleep(self.period);
-            loop {
-                match self.receiver.try_recv() {
-                    Err(TryRecvError::Empty) => break,
-                    Err(TryRecvError::Disconnected) => return,
-                    Ok(msg) => self.process(msg).unwrap(),
-                };
-            }
+            // Sleep until we get a message or until the next scheduled
+            // message or velocity tick is due. This way messages are
+            // handled as soon as they arrive and we don't wake up at
+            // all while idle.
+            let received = match self.next_deadline() {
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+                Some(deadline) => {
+                    let now = self.clock.now().expect("Failed to get the time");
+                    let timeout = if deadline > now {
+                        Duration::from(deadline - now)
+                    } else {
+                        Duration::from_secs(0)
+                    };
+                    self.receiver.recv_timeout(timeout)
+                }
+            };
+            match received {
+                Ok(msg) => self.schedule(msg),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
             let t = self.clock.now().expect("Failed to get the time");
-            self.send_velocity(t).unwrap();
+            self.process_due(t).unwrap();
+            self.pulse_turbos(t).unwrap();
+            self.tick(t).unwrap();
         }
     }
 
+    fn next_deadline(&self) -> Option<Time> {
+        let next_scheduled = self.scheduled.peek().map(Scheduled::time);
+        let next_turbo = self
+            .turbos
+            .values()
+            .map(Turbo::next_change)
+            .fold(None, |a, b| earliest(a, Some(b)));
+        earliest(earliest(self.next_tick, next_scheduled), next_turbo)
+    }
+
+    fn schedule(&mut self, msg: WorkerMessage) {
+        self.scheduled.push(Scheduled(self.next_seq, msg));
+        self.next_seq += 1;
+    }
+
+    /// Process every message whose time has come, in order
+    fn process_due(&mut self, now: Time) -> InputResult<()> {
+        while let Some(next) = self.scheduled.peek() {
+            if next.time() > now {
+                break;
+            }
+            let Scheduled(_, msg) = self.scheduled.pop().unwrap();
+            self.process(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Send every turbo press and release that's due, in order
+    fn pulse_turbos(&mut self, now: Time) -> InputResult<()> {
+        let mut due = Vec::new();
+        for (key, turbo) in self.turbos.iter_mut() {
+            while let Some((time, value)) = turbo.step(now) {
+                due.push((time, *key, value));
+            }
+        }
+        due.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        for (time, key, value) in due {
+            self.button(time, key, value)?;
+        }
+        Ok(())
+    }
+
+    fn set_turbo(
+        &mut self,
+        time: Time,
+        key: EV_KEY,
+        hz: f64,
+        duty: f64,
+        enabled: bool,
+    ) -> InputResult<()> {
+        // Don't leave the key stuck down, whether we're stopping or
+        // starting over at a new rate
+        if let Some(old) = self.turbos.remove(&key) {
+            if old.pressed {
+                self.button(time, key, 0)?;
+            }
+        }
+        if enabled {
+            self.turbos.insert(key, Turbo::new(time, hz, duty));
+        }
+        Ok(())
+    }
+
+    fn is_moving(&self) -> bool {
+        self.x_interp.is_some()
+            || self.y_interp.is_some()
+            || self.vwheel.interp.is_some()
+            || self.hwheel.interp.is_some()
+    }
+
+    /// Send velocity movement if a tick is due and work out when the
+    /// next one will be.
+    fn tick(&mut self, now: Time) -> InputResult<()> {
+        if !self.is_moving() {
+            self.next_tick = None;
+            return Ok(());
+        }
+        match self.next_tick {
+            Some(deadline) if deadline > now => (),
+            Some(_) => {
+                self.send_velocity(now)?;
+                self.next_tick = Some(now + Time::from(self.period));
+            }
+            None => self.next_tick = Some(now + Time::from(self.period)),
+        };
+        Ok(())
+    }
+
     fn process(&mut self, WorkerMessage(time, op): WorkerMessage) -> InputResult<()> {
         match op {
             InputOp::Button { key, value } => self.button(time, key, value)?,
+            InputOp::Axis { axis, value } => self.axis(time, axis, value)?,
             InputOp::XAbs { x } => {
-                self.write_x_move(time - Time::from(MOUSE_PERIOD / 2), f64::MIN)?;
+                self.write_x_move(time - Time::from(self.period / 2), f64::MIN)?;
                 self.write_x_move(time, x)?;
                 self.syn(time)?;
             }
             InputOp::YAbs { y } => {
-                self.write_x_move(time - Time::from(MOUSE_PERIOD / 2), f64::MIN)?;
+                self.write_x_move(time - Time::from(self.period / 2), f64::MIN)?;
                 self.write_x_move(time, y)?;
                 self.syn(time)?;
             }
@@ -238,6 +964,23 @@ impl VirtualInputWorker {
             }
             InputOp::XVel { dxdt } => self.set_x_vel(time, dxdt)?,
             InputOp::YVel { dydt } => self.set_y_vel(time, dydt)?,
+            InputOp::Scroll { dy, dx } => {
+                let dy = self.vwheel.dither(dy);
+                self.vwheel.write(&self.device, time, dy)?;
+                let dx = self.hwheel.dither(dx);
+                self.hwheel.write(&self.device, time, dx)?;
+                self.syn(time)?;
+            }
+            InputOp::ScrollVel { dydt, dxdt } => {
+                self.vwheel.set_vel(time, dydt);
+                self.hwheel.set_vel(time, dxdt);
+            }
+            InputOp::Turbo {
+                key,
+                hz,
+                duty,
+                enabled,
+            } => self.set_turbo(time, key, hz, duty, enabled)?,
         };
         Ok(())
     }
@@ -258,6 +1001,18 @@ impl VirtualInputWorker {
             value,
         })?;
         self.syn(time)?;
+        note_emitted(&self.state, EventCode::EV_KEY(key), value);
+        Ok(())
+    }
+
+    fn axis(&self, time: Time, axis: EV_ABS, value: i32) -> InputResult<()> {
+        self.device.write_event(&InputEvent {
+            time: time.into(),
+            event_code: EventCode::EV_ABS(axis),
+            value,
+        })?;
+        self.syn(time)?;
+        note_emitted(&self.state, EventCode::EV_ABS(axis), value);
         Ok(())
     }
 
@@ -312,7 +1067,54 @@ impl VirtualInputWorker {
             let dy = interp.interpolate(t);
             self.write_y_move(t, dy as f64)?;
         }
+        let dy = self.vwheel.tick(t);
+        self.vwheel.write(&self.device, t, dy)?;
+        let dx = self.hwheel.tick(t);
+        self.hwheel.write(&self.device, t, dx)?;
         self.syn(t)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(turbo: &mut Turbo, now: f64) -> Option<(f64, i32)> {
+        turbo
+            .step(Time::from(now))
+            .map(|(time, value)| (f64::from(time), value))
+    }
+
+    #[test]
+    fn turbo_pulses_on_schedule() {
+        let mut turbo = Turbo::new(Time::from(0.0), 4.0, 0.5);
+        assert_eq!(step(&mut turbo, 0.0), Some((0.0, 1)));
+        assert_eq!(step(&mut turbo, 0.1), None);
+        assert_eq!(step(&mut turbo, 0.125), Some((0.125, 0)));
+        assert_eq!(step(&mut turbo, 0.2), None);
+        assert_eq!(step(&mut turbo, 0.25), Some((0.25, 1)));
+    }
+
+    #[test]
+    fn turbo_duty_sets_release_time() {
+        let mut turbo = Turbo::new(Time::from(1.0), 2.0, 0.25);
+        assert_eq!(step(&mut turbo, 1.0), Some((1.0, 1)));
+        assert_eq!(step(&mut turbo, 1.1), None);
+        assert_eq!(step(&mut turbo, 1.125), Some((1.125, 0)));
+        assert_eq!(step(&mut turbo, 1.5), Some((1.5, 1)));
+    }
+
+    #[test]
+    fn turbo_skips_missed_pulses() {
+        let mut turbo = Turbo::new(Time::from(0.0), 4.0, 0.5);
+        assert_eq!(step(&mut turbo, 0.0), Some((0.0, 1)));
+        // The overdue release still goes out, but the presses we missed
+        // after it don't
+        assert_eq!(step(&mut turbo, 1.1), Some((0.125, 0)));
+        assert_eq!(step(&mut turbo, 1.1), Some((1.0, 1)));
+        assert_eq!(step(&mut turbo, 1.1), None);
+        assert_eq!(step(&mut turbo, 1.125), Some((1.125, 0)));
+        assert_eq!(step(&mut turbo, 1.25), Some((1.25, 1)));
+    }
+}